@@ -1,13 +1,17 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, ItemFn};
+use proc_macro2::{Delimiter, Ident, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, FnArg, ImplItemFn, ItemFn, Receiver};
 
 /// Attribute macro for async main functions that need Apple framework support.
 ///
 /// On macOS, this initializes the tokio runtime and runs the user's async main
 /// on a background thread while keeping the main thread available for Apple APIs.
 ///
-/// On non-macOS platforms, this is equivalent to `#[tokio::main]`.
+/// On non-macOS platforms, this is equivalent to `#[tokio::main]`, unless
+/// apple-main's `emulated-main-thread` feature is enabled, in which case it
+/// behaves as on macOS with a portable run loop on the main thread.
 ///
 /// # Example
 ///
@@ -29,21 +33,16 @@ pub fn main(_attr: TokenStream, item: TokenStream) -> TokenStream {
         fn main() {
             #[cfg(target_os = "macos")]
             {
-                let rt = ::apple_main::init_runtime();
-                rt.spawn(async {
-                    #fn_block
-                    ::apple_main::__internal::exit_main_loop(0);
-                });
+                ::apple_main::__internal::spawn_main_body(
+                    ::apple_main::init_runtime(),
+                    async #fn_block,
+                );
                 ::apple_main::__internal::run_main_loop();
             }
 
             #[cfg(not(target_os = "macos"))]
             {
-                ::tokio::runtime::Builder::new_multi_thread()
-                    .enable_all()
-                    .build()
-                    .expect("failed to create tokio runtime")
-                    .block_on(async #fn_block);
+                ::apple_main::__run_main!(#fn_block);
             }
         }
     };
@@ -163,3 +162,177 @@ pub fn harness_test(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
     expanded.into()
 }
+
+/// Attribute macro for functions and methods that belong on the main thread.
+///
+/// `#[apple_main::main_thread]` (or `#[apple_main::main_thread(assert)]`)
+/// inserts a debug assertion that the function is called on the main thread,
+/// naming the function on failure.
+///
+/// `#[apple_main::main_thread(dispatch)]` turns a sync function into an
+/// `async fn` that runs its body on the main thread through `on_main`, so
+/// callers can use it from any tokio task. Arguments and the return type must
+/// be `Send + 'static`. Methods may take `self` by value, or `&self` if `Self`
+/// is `Clone + Send + 'static`, in which case the body runs on a clone;
+/// `&mut self` is rejected.
+///
+/// # Example
+///
+/// ```ignore
+/// impl VmHandle {
+///     #[apple_main::main_thread(dispatch)]
+///     fn state(&self) -> VmState {
+///         self.vm.state() // runs on the main thread
+///     }
+///
+///     #[apple_main::main_thread]
+///     fn start_on_main(&self) {
+///         // debug-asserts that the caller is on the main thread
+///     }
+/// }
+///
+/// let state = handle.state().await;
+/// ```
+#[proc_macro_attribute]
+pub fn main_thread(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mode = parse_macro_input!(attr as Option<Ident>);
+    let input = parse_macro_input!(item as ImplItemFn);
+
+    let expanded = match mode.as_ref().map(Ident::to_string).as_deref() {
+        None | Some("assert") => Ok(assert_main_thread(input)),
+        Some("dispatch") => dispatch_to_main(input),
+        Some(_) => Err(syn::Error::new(
+            mode.span(),
+            "expected `assert` or `dispatch`",
+        )),
+    };
+
+    expanded
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn assert_main_thread(mut input: ImplItemFn) -> TokenStream2 {
+    let message = format!("`{}` must be called on the main thread", input.sig.ident);
+    let block = &input.block;
+    input.block = syn::parse_quote! {{
+        ::std::debug_assert!(::apple_main::is_main_thread(), #message);
+        #block
+    }};
+    quote!(#input)
+}
+
+fn dispatch_to_main(mut input: ImplItemFn) -> syn::Result<TokenStream2> {
+    if let Some(asyncness) = input.sig.asyncness {
+        return Err(syn::Error::new(
+            asyncness.span(),
+            "`main_thread(dispatch)` makes the function async; remove `async`",
+        ));
+    }
+
+    let moved_self = match input.sig.receiver() {
+        None => quote!(),
+        Some(Receiver {
+            reference: None,
+            mutability: None,
+            ..
+        }) => quote!(let __apple_main_self = self;),
+        Some(Receiver {
+            reference: None,
+            mutability: Some(_),
+            ..
+        }) => quote!(let mut __apple_main_self = self;),
+        Some(Receiver {
+            reference: Some(_),
+            mutability: None,
+            ..
+        }) => quote!(let __apple_main_self = ::std::clone::Clone::clone(self);),
+        Some(receiver) => {
+            return Err(syn::Error::new(
+                receiver.span(),
+                "`main_thread(dispatch)` cannot take `&mut self`: the body runs on \
+                 the main thread after the borrow ends",
+            ))
+        }
+    };
+    if let Some(FnArg::Receiver(receiver)) = input.sig.inputs.first_mut() {
+        // Only the moved copy is mutated, so a `mut` here would be unused.
+        receiver.mutability = None;
+    }
+
+    for arg in &input.sig.inputs {
+        if let FnArg::Typed(pat) = arg {
+            if let syn::Type::Reference(reference) = &*pat.ty {
+                return Err(syn::Error::new(
+                    reference.span(),
+                    "`main_thread(dispatch)` arguments are moved to the main thread \
+                     and cannot be references",
+                ));
+            }
+        }
+    }
+
+    let body = replace_self(input.block.to_token_stream());
+    let span = input.block.span();
+    input.sig.asyncness = Some(syn::Token![async](input.sig.ident.span()));
+    input.block = syn::parse2(quote_spanned! {span=> {
+        #moved_self
+        ::apple_main::on_main(move || #body).await
+    }})?;
+
+    Ok(quote!(#input))
+}
+
+/// Replaces uses of `self` with the variable holding the moved receiver,
+/// leaving `self::` paths alone. Nested `fn`, `impl` and `trait` items are
+/// left alone too, since a `self` inside them is their own.
+fn replace_self(tokens: TokenStream2) -> TokenStream2 {
+    let mut tokens = tokens.into_iter().peekable();
+    let mut output = Vec::new();
+    // Set from a nested item's keyword until the end of its body.
+    let mut in_item = false;
+
+    while let Some(token) = tokens.next() {
+        if in_item {
+            in_item = !matches!(
+                &token,
+                TokenTree::Group(group) if group.delimiter() == Delimiter::Brace
+            );
+            output.push(token);
+            continue;
+        }
+
+        output.push(match token {
+            TokenTree::Ident(ident) if ident == "self" => {
+                let is_path = matches!(
+                    tokens.peek(),
+                    Some(TokenTree::Punct(punct)) if punct.as_char() == ':'
+                );
+                if is_path {
+                    TokenTree::Ident(ident)
+                } else {
+                    TokenTree::Ident(Ident::new("__apple_main_self", ident.span()))
+                }
+            }
+            // `fn` followed by a name starts an item; followed by `(` it is a
+            // function pointer type.
+            TokenTree::Ident(ident)
+                if ident == "impl"
+                    || ident == "trait"
+                    || (ident == "fn" && matches!(tokens.peek(), Some(TokenTree::Ident(_)))) =>
+            {
+                in_item = true;
+                TokenTree::Ident(ident)
+            }
+            TokenTree::Group(group) => {
+                let mut replaced =
+                    proc_macro2::Group::new(group.delimiter(), replace_self(group.stream()));
+                replaced.set_span(group.span());
+                TokenTree::Group(replaced)
+            }
+            other => other,
+        });
+    }
+
+    output.into_iter().collect()
+}
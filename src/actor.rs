@@ -0,0 +1,401 @@
+use std::fmt;
+use std::future::Future;
+use std::panic::Location;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::dispatch::catch;
+use crate::error::{DispatchError, MainPanic};
+use crate::executor::spawn_main;
+
+/// State that lives on the main thread and is driven by messages.
+///
+/// Each message is handled on the main thread, one at a time, in the order it
+/// was delivered to the mailbox. The actor itself does not need to be `Send`;
+/// it is created by the factory passed to [`spawn_actor`] on the main thread
+/// and dropped there once it stops.
+///
+/// ```ignore
+/// struct VmManager { vm: Option<VZVirtualMachine> }
+///
+/// enum Command { Start, Stop, State }
+///
+/// impl apple_main::MainActor for VmManager {
+///     type Message = Command;
+///     type Reply = VmState;
+///
+///     fn handle(&mut self, msg: Command) -> VmState {
+///         // runs on the main thread
+///     }
+/// }
+///
+/// let manager = apple_main::spawn_actor(32, || VmManager { vm: None });
+/// let state = manager.ask(Command::Start).await?;
+/// ```
+pub trait MainActor: 'static {
+    /// The messages this actor accepts.
+    type Message: Send + 'static;
+    /// The reply produced for each message.
+    type Reply: Send + 'static;
+
+    /// Handles one message on the main thread.
+    fn handle(&mut self, msg: Self::Message) -> Self::Reply;
+
+    /// Called on the main thread once the actor has stopped, before it is
+    /// dropped.
+    fn stopped(&mut self) {}
+}
+
+enum Envelope<A: MainActor> {
+    Message {
+        msg: A::Message,
+        reply: Option<oneshot::Sender<Result<A::Reply, DispatchError>>>,
+        location: &'static Location<'static>,
+    },
+    Stop,
+}
+
+/// Lifecycle shared by all references to one actor.
+struct Lifecycle {
+    stopped: watch::Receiver<bool>,
+    // A panic from a `send` message, which has no caller to report to.
+    panic: Mutex<Option<MainPanic>>,
+}
+
+/// A cloneable, `Send` reference to a [`MainActor`] running on the main thread.
+///
+/// Messages from all clones go through one bounded mailbox: senders wait for
+/// capacity when it is full. The actor stops when [`stop`](ActorRef::stop) is
+/// called, when its handler panics, or when every reference is dropped.
+pub struct ActorRef<A: MainActor> {
+    tx: mpsc::Sender<Envelope<A>>,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl<A: MainActor> ActorRef<A> {
+    /// Delivers `msg` to the actor without waiting for it to be handled.
+    ///
+    /// Waits for mailbox capacity, and fails with [`DispatchError::Stopped`]
+    /// if the actor has stopped.
+    #[track_caller]
+    pub fn send(&self, msg: A::Message) -> impl Future<Output = Result<(), DispatchError>> + Send {
+        let location = Location::caller();
+        let tx = self.tx.clone();
+
+        async move {
+            tx.send(Envelope::Message {
+                msg,
+                reply: None,
+                location,
+            })
+            .await
+            .map_err(|_| DispatchError::Stopped)
+        }
+    }
+
+    /// Delivers `msg` to the actor and waits for its reply.
+    ///
+    /// If the handler panics, the panic is returned as
+    /// [`DispatchError::Panicked`] and the actor stops.
+    #[track_caller]
+    pub fn ask(
+        &self,
+        msg: A::Message,
+    ) -> impl Future<Output = Result<A::Reply, DispatchError>> + Send {
+        let location = Location::caller();
+        let tx = self.tx.clone();
+
+        async move {
+            let (reply, rx) = oneshot::channel();
+            tx.send(Envelope::Message {
+                msg,
+                reply: Some(reply),
+                location,
+            })
+            .await
+            .map_err(|_| DispatchError::Stopped)?;
+
+            rx.await.unwrap_or(Err(DispatchError::Stopped))
+        }
+    }
+
+    /// Asks the actor to stop once it has handled every message sent before
+    /// this call. Later messages are rejected.
+    pub fn stop(&self) -> impl Future<Output = ()> + Send {
+        let tx = self.tx.clone();
+
+        async move {
+            let _ = tx.send(Envelope::Stop).await;
+        }
+    }
+
+    /// Waits for the actor to stop.
+    ///
+    /// If the actor stopped because a [`send`](ActorRef::send) message
+    /// panicked, the first caller to join receives the panic.
+    pub fn join(&self) -> impl Future<Output = Result<(), DispatchError>> + Send {
+        let lifecycle = Arc::clone(&self.lifecycle);
+
+        async move {
+            let mut stopped = lifecycle.stopped.clone();
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+
+            match lifecycle
+                .panic
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take()
+            {
+                Some(panic) => Err(DispatchError::Panicked(panic)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Whether the actor has stopped.
+    pub fn is_stopped(&self) -> bool {
+        *self.lifecycle.stopped.borrow() || self.tx.is_closed()
+    }
+}
+
+impl<A: MainActor> Clone for ActorRef<A> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            lifecycle: Arc::clone(&self.lifecycle),
+        }
+    }
+}
+
+impl<A: MainActor> fmt::Debug for ActorRef<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActorRef")
+            .field("actor", &std::any::type_name::<A>())
+            .field("stopped", &self.is_stopped())
+            .finish()
+    }
+}
+
+/// Starts a [`MainActor`] on the main thread with a mailbox of `capacity`
+/// messages.
+///
+/// `f` creates the actor on the main thread. On non-macOS platforms actors run
+/// on the same background thread as `spawn_main` futures. If no main loop
+/// starts within the [grace period](crate::set_main_loop_grace_period), the
+/// actor never starts and behaves as if it had stopped.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+#[track_caller]
+pub fn spawn_actor<A, F>(capacity: usize, f: F) -> ActorRef<A>
+where
+    A: MainActor,
+    F: FnOnce() -> A + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<Envelope<A>>(capacity);
+    let (stopped_tx, stopped_rx) = watch::channel(false);
+    let lifecycle = Arc::new(Lifecycle {
+        stopped: stopped_rx,
+        panic: Mutex::new(None),
+    });
+
+    let location = Location::caller();
+    let actor_lifecycle = Arc::clone(&lifecycle);
+    // Detached: the actor's lifetime is tied to its mailbox, not the handle.
+    drop(spawn_main(move || async move {
+        let mut actor = match catch(f, location) {
+            Ok(actor) => actor,
+            Err(err) => {
+                rx.close();
+                if let DispatchError::Panicked(panic) = err {
+                    *actor_lifecycle
+                        .panic
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner()) = Some(panic);
+                }
+                let _ = stopped_tx.send(true);
+                return;
+            }
+        };
+
+        while let Some(envelope) = rx.recv().await {
+            let Envelope::Message {
+                msg,
+                reply,
+                location,
+            } = envelope
+            else {
+                break;
+            };
+
+            match (catch(|| actor.handle(msg), location), reply) {
+                (Ok(value), Some(reply)) => {
+                    let _ = reply.send(Ok(value));
+                }
+                (Ok(_), None) => {}
+                (Err(err), Some(reply)) => {
+                    let _ = reply.send(Err(err));
+                    break;
+                }
+                (Err(DispatchError::Panicked(panic)), None) => {
+                    *actor_lifecycle
+                        .panic
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner()) = Some(panic);
+                    break;
+                }
+                (Err(_), None) => break,
+            }
+        }
+
+        rx.close();
+        actor.stopped();
+        drop(actor);
+        let _ = stopped_tx.send(true);
+    }));
+
+    ActorRef { tx, lifecycle }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(u32);
+
+    impl MainActor for Counter {
+        type Message = u32;
+        type Reply = u32;
+
+        fn handle(&mut self, msg: u32) -> u32 {
+            if msg == 0 {
+                panic!("zero is not allowed");
+            }
+            self.0 += msg;
+            self.0
+        }
+    }
+
+    #[test]
+    fn actor_ref_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ActorRef<Counter>>();
+    }
+
+    // On macOS, or with an emulated main thread, these need a running main
+    // loop; see harness_integration.rs.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use super::*;
+        use std::rc::Rc;
+
+        #[tokio::test]
+        async fn ask_returns_replies_in_order() {
+            let counter = spawn_actor(4, || Counter(0));
+            counter.send(1).await.unwrap();
+            counter.send(2).await.unwrap();
+            assert_eq!(counter.ask(3).await.unwrap(), 6);
+        }
+
+        #[tokio::test]
+        async fn clones_share_one_actor() {
+            let counter = spawn_actor(4, || Counter(0));
+            let other = counter.clone();
+            counter.send(1).await.unwrap();
+            assert_eq!(other.ask(1).await.unwrap(), 2);
+        }
+
+        #[tokio::test]
+        async fn stop_handles_earlier_messages_then_rejects() {
+            let counter = spawn_actor(4, || Counter(0));
+            let mut ask = std::pin::pin!(counter.ask(5));
+            // Polling the ask once puts it in the mailbox ahead of the stop.
+            let early = tokio::select! {
+                biased;
+                reply = &mut ask => Some(reply),
+                () = std::future::ready(()) => None,
+            };
+            counter.stop().await;
+            counter.join().await.unwrap();
+
+            let reply = match early {
+                Some(reply) => reply,
+                None => ask.await,
+            };
+            assert_eq!(reply.unwrap(), 5);
+            assert!(counter.is_stopped());
+            assert!(matches!(counter.send(1).await, Err(DispatchError::Stopped)));
+        }
+
+        #[tokio::test]
+        async fn panicking_ask_reports_panic_and_stops_actor() {
+            let counter = spawn_actor(4, || Counter(0));
+            let err = counter.ask(0).await.unwrap_err();
+            assert!(matches!(err, DispatchError::Panicked(_)));
+
+            counter.join().await.unwrap();
+            assert!(matches!(counter.ask(1).await, Err(DispatchError::Stopped)));
+        }
+
+        #[tokio::test]
+        async fn panicking_send_is_reported_by_join() {
+            let counter = spawn_actor(4, || Counter(0));
+            counter.send(0).await.unwrap();
+            let err = counter.join().await.unwrap_err();
+            let DispatchError::Panicked(panic) = err else {
+                panic!("expected Panicked, got {err:?}");
+            };
+            assert_eq!(panic.message(), Some("zero is not allowed"));
+        }
+
+        #[tokio::test]
+        async fn panicking_factory_is_reported_by_join() {
+            let counter = spawn_actor(4, || -> Counter { panic!("no counter") });
+            let err = counter.join().await.unwrap_err();
+            let DispatchError::Panicked(panic) = err else {
+                panic!("expected Panicked, got {err:?}");
+            };
+            assert_eq!(panic.message(), Some("no counter"));
+            assert!(matches!(counter.send(1).await, Err(DispatchError::Stopped)));
+        }
+
+        #[tokio::test]
+        async fn actor_state_need_not_be_send() {
+            struct Local(Rc<u32>);
+
+            impl MainActor for Local {
+                type Message = ();
+                type Reply = u32;
+
+                fn handle(&mut self, _: ()) -> u32 {
+                    *self.0
+                }
+            }
+
+            let local = spawn_actor(1, || Local(Rc::new(7)));
+            assert_eq!(local.ask(()).await.unwrap(), 7);
+        }
+
+        #[tokio::test]
+        async fn dropping_every_ref_stops_actor() {
+            struct Probe(Option<oneshot::Sender<()>>);
+
+            impl MainActor for Probe {
+                type Message = ();
+                type Reply = ();
+
+                fn handle(&mut self, _: ()) {}
+
+                fn stopped(&mut self) {
+                    let _ = self.0.take().unwrap().send(());
+                }
+            }
+
+            let (tx, rx) = oneshot::channel();
+            drop(spawn_actor(1, move || Probe(Some(tx))));
+            rx.await.unwrap();
+        }
+    }
+}
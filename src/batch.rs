@@ -0,0 +1,140 @@
+use crate::dispatch::{try_on_main, MainTask, TryMainTask};
+
+type Step<R> = Box<dyn FnOnce() -> R + Send>;
+
+/// Closures collected to run back-to-back in a single main-thread turn.
+///
+/// The whole batch is dispatched as one job, so it costs one run-loop wakeup,
+/// and no other main-thread work runs between its closures. Closures run in
+/// the order they were added; if one panics, the rest are skipped and the
+/// panic is reported like any other dispatched closure's.
+///
+/// Every closure returns the batch's result type, here
+/// `Result<(), ConfigError>`:
+///
+/// ```ignore
+/// let (cpus, memory) = (config.clone(), config.clone());
+/// let results = apple_main::MainBatch::<Result<(), ConfigError>>::new()
+///     .then(move || Ok(cpus.set_cpu_count(4)))
+///     .then(move || Ok(memory.set_memory_size(8 << 30)))
+///     .then(move || config.validate())
+///     .run()
+///     .await;
+/// ```
+#[must_use = "a MainBatch does nothing until it is run"]
+pub struct MainBatch<R> {
+    steps: Vec<Step<R>>,
+}
+
+impl<R> MainBatch<R>
+where
+    R: Send + 'static,
+{
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    /// Adds `f` to the end of the batch.
+    pub fn then<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() -> R + Send + 'static,
+    {
+        self.steps.push(Box::new(f));
+        self
+    }
+
+    /// The number of closures in the batch.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Whether the batch has no closures.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Dispatches the batch to the main thread and returns a [`MainTask`]
+    /// for all of its results, in the order the closures were added.
+    #[track_caller]
+    pub fn run(self) -> MainTask<Vec<R>> {
+        MainTask(self.try_run())
+    }
+
+    /// Like [`run`](Self::run), but reports failures as a
+    /// [`DispatchError`](crate::DispatchError) like
+    /// [`try_on_main`](crate::try_on_main).
+    #[track_caller]
+    pub fn try_run(self) -> TryMainTask<Vec<R>> {
+        let steps = self.steps;
+        try_on_main(move || steps.into_iter().map(|step| step()).collect())
+    }
+}
+
+impl<R> Default for MainBatch<R>
+where
+    R: Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R> std::fmt::Debug for MainBatch<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MainBatch")
+            .field("len", &self.steps.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_tracks_its_length() {
+        let batch = MainBatch::new();
+        assert!(batch.is_empty());
+        let batch = batch.then(|| 1).then(|| 2);
+        assert_eq!(batch.len(), 2);
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use crate::{DispatchError, MainBatch};
+        use std::sync::{Arc, Mutex};
+
+        #[tokio::test]
+        async fn batch_returns_results_in_order() {
+            let results = MainBatch::new()
+                .then(|| 1)
+                .then(|| 2)
+                .then(|| 3)
+                .run()
+                .await;
+            assert_eq!(results, [1, 2, 3]);
+        }
+
+        #[tokio::test]
+        async fn empty_batch_returns_no_results() {
+            let results: Vec<()> = MainBatch::new().run().await;
+            assert!(results.is_empty());
+        }
+
+        #[tokio::test]
+        async fn panic_skips_rest_of_batch() {
+            let ran = Arc::new(Mutex::new(Vec::new()));
+            let (first, last) = (Arc::clone(&ran), Arc::clone(&ran));
+
+            let result = MainBatch::new()
+                .then(move || first.lock().unwrap().push(1))
+                .then(|| panic!("boom"))
+                .then(move || last.lock().unwrap().push(3))
+                .try_run()
+                .await;
+            assert!(matches!(result, Err(DispatchError::Panicked(_))));
+            assert_eq!(*ran.lock().unwrap(), [1]);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+
+use crate::executor::{on_home_thread, on_home_thread_sync, on_main_async, schedule};
+
+/// Owns a value that lives on the main thread.
+struct Inner<T: 'static> {
+    value: ManuallyDrop<T>,
+}
+
+// SAFETY: `value` is only ever accessed through `get`, which is only called
+// from closures running on the home thread, and it is dropped there too. The
+// `Inner` itself may move between threads, but the `T` it owns never does.
+unsafe impl<T: 'static> Send for Inner<T> {}
+unsafe impl<T: 'static> Sync for Inner<T> {}
+
+impl<T: 'static> Inner<T> {
+    fn get(&self) -> &T {
+        debug_assert!(
+            on_home_thread(),
+            "MainBound value accessed off the main thread"
+        );
+        &self.value
+    }
+}
+
+impl<T: 'static> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // SAFETY: `value` is never used again after being taken here.
+        let value = unsafe { ManuallyDrop::take(&mut self.value) };
+
+        if on_home_thread() {
+            drop(value);
+        } else {
+            let value = AssertSend(value);
+            schedule(Box::new(move || drop(value)));
+        }
+    }
+}
+
+/// Moves a value to the home thread to be dropped there.
+struct AssertSend<T>(T);
+
+// SAFETY: only used to move a value that is never touched until it is dropped
+// on the home thread.
+unsafe impl<T> Send for AssertSend<T> {}
+
+/// A `Send + Sync` handle to a value that lives on the main thread.
+///
+/// The value is created on the main thread by [`on_main_bound`], only ever
+/// accessed there through [`with`](MainBound::with) and
+/// [`with_sync`](MainBound::with_sync), and always dropped there, even when the
+/// last handle is dropped on a tokio worker. This makes it possible to hold
+/// `!Send` framework objects in tokio tasks.
+///
+/// Handles are cheap to clone and share the same value. On non-macOS platforms
+/// the value lives on the same background thread that runs `spawn_main`
+/// futures.
+pub struct MainBound<T: 'static> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: 'static> MainBound<T> {
+    /// Runs `f` with a reference to the value on the main thread.
+    ///
+    /// A panic in `f` is resumed in the awaiting task.
+    #[track_caller]
+    pub fn with<F, R>(&self, f: F) -> impl Future<Output = R> + Send
+    where
+        F: FnOnce(&T) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        on_main_async(move || std::future::ready(f(inner.get())))
+    }
+
+    /// Runs `f` with a reference to the value on the main thread, blocking
+    /// until it returns. Runs inline if already on the main thread.
+    #[track_caller]
+    pub fn with_sync<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        on_home_thread_sync(move || f(inner.get()))
+    }
+}
+
+impl<T: 'static> Clone for MainBound<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for MainBound<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MainBound")
+            .field("type", &std::any::type_name::<T>())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Creates a value on the main thread and returns a [`MainBound`] handle to it.
+///
+/// `T` does not need to be `Send`: it is created, used, and dropped on the main
+/// thread.
+///
+/// ```ignore
+/// let vm = apple_main::on_main_bound(|| VZVirtualMachine::new(config)).await;
+/// vm.with(|vm| vm.start()).await;
+/// ```
+#[track_caller]
+pub fn on_main_bound<F, T>(f: F) -> impl Future<Output = MainBound<T>> + Send
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: 'static,
+{
+    on_main_async(move || {
+        let bound = MainBound {
+            inner: Arc::new(Inner {
+                value: ManuallyDrop::new(f()),
+            }),
+        };
+        std::future::ready(bound)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn main_bound_is_send_and_sync_for_non_send_values() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MainBound<std::rc::Rc<u32>>>();
+    }
+
+    // On macOS, or with an emulated main thread, these need a running main
+    // loop; see harness_integration.rs.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use super::super::*;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        #[tokio::test]
+        async fn with_accesses_value_on_home_thread() {
+            let bound = on_main_bound(|| Rc::new(Cell::new(1))).await;
+            bound.with(|value| value.set(value.get() + 1)).await;
+            let result = bound.with(|value| (value.get(), on_home_thread())).await;
+            assert_eq!(result, (2, true));
+        }
+
+        #[tokio::test]
+        async fn with_sync_accesses_value_on_home_thread() {
+            let bound = on_main_bound(|| Rc::new(41)).await;
+            let result = bound.with_sync(|value| **value + 1);
+            assert_eq!(result, 42);
+        }
+
+        #[tokio::test]
+        async fn value_is_dropped_on_home_thread() {
+            struct Probe(std::sync::mpsc::Sender<bool>);
+
+            impl Drop for Probe {
+                fn drop(&mut self) {
+                    let _ = self.0.send(on_home_thread());
+                }
+            }
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            let bound = on_main_bound(move || Probe(tx)).await;
+            drop(bound);
+            assert!(rx.recv().unwrap());
+        }
+
+        #[cfg(debug_assertions)]
+        #[test]
+        #[should_panic(expected = "off the main thread")]
+        fn get_off_home_thread_is_caught_in_debug() {
+            let inner = Inner {
+                value: ManuallyDrop::new(1),
+            };
+            let _ = inner.get();
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+/// The bound on outstanding main-thread closures.
+pub(crate) static MAIN_QUEUE: Capacity = Capacity::new();
+
+/// Limits how many closures dispatched by the [`on_main`](crate::on_main) and
+/// [`on_main_sync`](crate::on_main_sync) families, or by
+/// [`on_main_coalesced`](crate::on_main_coalesced), may be outstanding on the
+/// main thread at once, counting from when they are queued until they finish
+/// running. `None`, the default, means no limit.
+///
+/// Once the limit is reached, `on_main` waits asynchronously for capacity
+/// when the returned task is polled, and `on_main_sync` blocks until there is
+/// some. [`try_on_main_now`](crate::try_on_main_now) fails right away with
+/// [`DispatchError::QueueFull`](crate::DispatchError::QueueFull) instead.
+/// Closures that run inline, because they were dispatched from the main
+/// thread, never count toward the limit. Work queued by
+/// [`spawn_main`](crate::spawn_main) futures, timers and other main-thread
+/// helpers counts toward it but never waits for room, since closures already
+/// queued may depend on it.
+///
+/// Lowering the limit below the number of outstanding closures does not
+/// affect those already queued.
+pub fn set_main_queue_limit(limit: Option<usize>) {
+    MAIN_QUEUE.set_limit(limit);
+}
+
+/// The limit set by [`set_main_queue_limit`], if any.
+pub fn main_queue_limit() -> Option<usize> {
+    MAIN_QUEUE.limit()
+}
+
+/// A counting bound that sync callers can block on and async callers can
+/// await.
+pub(crate) struct Capacity {
+    limit: AtomicUsize,
+    outstanding: Mutex<usize>,
+    freed: Condvar,
+    freed_async: Notify,
+}
+
+impl Capacity {
+    pub(crate) const fn new() -> Self {
+        Self {
+            limit: AtomicUsize::new(usize::MAX),
+            outstanding: Mutex::new(0),
+            freed: Condvar::new(),
+            freed_async: Notify::const_new(),
+        }
+    }
+
+    fn limit(&self) -> Option<usize> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|limit| *limit != usize::MAX)
+    }
+
+    fn set_limit(&self, limit: Option<usize>) {
+        self.limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+        // A raised limit may let waiters through.
+        self.notify();
+    }
+
+    /// Takes a slot if below the limit.
+    pub(crate) fn try_acquire(&'static self) -> Option<Permit> {
+        let mut outstanding = self.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+        self.take(&mut outstanding)
+    }
+
+    /// Waits asynchronously for a slot.
+    pub(crate) async fn acquire(&'static self) -> Permit {
+        loop {
+            // Register before checking, so a release in between is not missed.
+            let mut freed = pin!(self.freed_async.notified());
+            freed.as_mut().enable();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            freed.await;
+        }
+    }
+
+    /// Takes a slot even if at the limit, for work that must not wait.
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    pub(crate) fn acquire_now(&'static self) -> Permit {
+        *self.outstanding.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        Permit(self)
+    }
+
+    /// Blocks until a slot is free, or returns `None` once `deadline` passes.
+    pub(crate) fn acquire_blocking(&'static self, deadline: Option<Instant>) -> Option<Permit> {
+        let mut outstanding = self.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(permit) = self.take(&mut outstanding) {
+                return Some(permit);
+            }
+            outstanding = match deadline {
+                None => self
+                    .freed
+                    .wait(outstanding)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let remaining = deadline.checked_duration_since(Instant::now())?;
+                    self.freed
+                        .wait_timeout(outstanding, remaining)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+    }
+
+    fn take(&'static self, outstanding: &mut usize) -> Option<Permit> {
+        if *outstanding >= self.limit.load(Ordering::Relaxed) {
+            return None;
+        }
+        *outstanding += 1;
+        Some(Permit(self))
+    }
+
+    fn notify(&self) {
+        self.freed.notify_all();
+        self.freed_async.notify_waiters();
+    }
+}
+
+/// A slot taken from a [`Capacity`], released when dropped.
+pub(crate) struct Permit(&'static Capacity);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.outstanding.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.0.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn capacity(limit: usize) -> &'static Capacity {
+        let capacity = Box::leak(Box::new(Capacity::new()));
+        capacity.set_limit(Some(limit));
+        capacity
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let capacity: &'static Capacity = Box::leak(Box::new(Capacity::new()));
+        assert_eq!(capacity.limit(), None);
+        let permits: Vec<_> = (0..1000).map(|_| capacity.try_acquire()).collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn try_acquire_fails_at_limit_until_a_permit_drops() {
+        let capacity = capacity(2);
+        let first = capacity.try_acquire().unwrap();
+        let _second = capacity.try_acquire().unwrap();
+        assert!(capacity.try_acquire().is_none());
+
+        drop(first);
+        assert!(capacity.try_acquire().is_some());
+    }
+
+    #[test]
+    fn acquire_blocking_times_out_or_wakes_on_release() {
+        let capacity = capacity(1);
+        let permit = capacity.try_acquire().unwrap();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(capacity.acquire_blocking(Some(deadline)).is_none());
+
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            drop(permit);
+        });
+        assert!(capacity.acquire_blocking(None).is_some());
+        releaser.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_release_or_raised_limit() {
+        let capacity = capacity(1);
+        let permit = capacity.try_acquire().unwrap();
+
+        let waiter = tokio::spawn(capacity.acquire());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+        drop(permit);
+        let permit = waiter.await.unwrap();
+
+        let waiter = tokio::spawn(capacity.acquire());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+        capacity.set_limit(Some(2));
+        waiter.await.unwrap();
+        drop(permit);
+    }
+}
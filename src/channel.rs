@@ -0,0 +1,349 @@
+use std::fmt;
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::dispatch::catch;
+use crate::executor::spawn_main;
+
+/// Why [`MainSender::try_send`] or [`MainConsumer::try_send`] could not send a
+/// value. The value is handed back either way.
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity; the receiver has fallen behind.
+    Full(T),
+    /// The receiving side has closed or been dropped.
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Returns the value that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> From<mpsc::error::TrySendError<T>> for TrySendError<T> {
+    fn from(err: mpsc::error::TrySendError<T>) -> Self {
+        match err {
+            mpsc::error::TrySendError::Full(value) => TrySendError::Full(value),
+            mpsc::error::TrySendError::Closed(value) => TrySendError::Closed(value),
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("main thread channel is full"),
+            TrySendError::Closed(_) => f.write_str("main thread channel is closed"),
+        }
+    }
+}
+
+impl<T> std::error::Error for TrySendError<T> {}
+
+/// The receiving side closed before [`MainConsumer::send`] could deliver a
+/// value. The value is handed back.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("main thread channel is closed")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// The sending half of a [`main_channel`], for use from main-thread callbacks.
+///
+/// Sending never blocks or awaits, so it works from plain delegate callbacks
+/// without an async context. Senders can be cloned; the receiver's stream ends
+/// once every sender is dropped.
+pub struct MainSender<T> {
+    tx: mpsc::Sender<T>,
+}
+
+impl<T> MainSender<T> {
+    /// Sends `value` to the tokio side without waiting.
+    ///
+    /// Fails with [`TrySendError::Full`] when the receiver has fallen
+    /// `capacity` values behind, leaving the caller to drop, coalesce, or
+    /// retry the value, and with [`TrySendError::Closed`] once the receiver
+    /// is closed or dropped.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.tx.try_send(value).map_err(TrySendError::from)
+    }
+
+    /// Whether the receiver has been closed or dropped.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+impl<T> Clone for MainSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for MainSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MainSender")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// The receiving half of a [`main_channel`], consumed on tokio as a
+/// [`Stream`].
+pub struct MainReceiver<T> {
+    rx: mpsc::Receiver<T>,
+}
+
+impl<T> MainReceiver<T> {
+    /// Waits for the next value, or returns `None` once every sender is
+    /// dropped and all sent values have been received.
+    pub async fn recv(&mut self) -> Option<T> {
+        self.rx.recv().await
+    }
+
+    /// Closes the channel: later sends fail, but values already sent can
+    /// still be received.
+    pub fn close(&mut self) {
+        self.rx.close();
+    }
+}
+
+impl<T> Stream for MainReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl<T> fmt::Debug for MainReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MainReceiver").finish_non_exhaustive()
+    }
+}
+
+/// Creates a bounded channel from main-thread callbacks to tokio.
+///
+/// ```ignore
+/// let (tx, mut states) = apple_main::main_channel(16);
+/// delegate.on_state_change(move |state| {
+///     // plain main-thread callback
+///     let _ = tx.try_send(state);
+/// });
+///
+/// while let Some(state) = states.next().await { /* on tokio */ }
+/// ```
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn main_channel<T: Send>(capacity: usize) -> (MainSender<T>, MainReceiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (MainSender { tx }, MainReceiver { rx })
+}
+
+/// A `Send` handle for delivering values from tokio to a consumer closure on
+/// the main thread, created by [`main_consumer`].
+///
+/// Handles can be cloned; once every handle is dropped, the consumer stops
+/// after handling the values already sent.
+pub struct MainConsumer<T> {
+    tx: mpsc::Sender<T>,
+}
+
+impl<T: Send> MainConsumer<T> {
+    /// Delivers `value` to the consumer, waiting while the channel is full.
+    ///
+    /// Fails once the consumer has stopped.
+    pub fn send(&self, value: T) -> impl Future<Output = Result<(), SendError<T>>> + Send {
+        let tx = self.tx.clone();
+
+        async move { tx.send(value).await.map_err(|err| SendError(err.0)) }
+    }
+
+    /// Delivers `value` to the consumer without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.tx.try_send(value).map_err(TrySendError::from)
+    }
+
+    /// Waits until the consumer has stopped.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    /// Whether the consumer has stopped.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+impl<T> Clone for MainConsumer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for MainConsumer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MainConsumer")
+            .field("closed", &self.tx.is_closed())
+            .finish()
+    }
+}
+
+/// Creates a bounded channel from tokio to `f`, which runs on the main thread
+/// for each value in the order they were sent.
+///
+/// Senders wait for capacity when the main thread falls `capacity` values
+/// behind. If `f` panics the consumer stops and later sends fail, as they do
+/// if no main loop starts within the
+/// [grace period](crate::set_main_loop_grace_period).
+///
+/// ```ignore
+/// let console = apple_main::main_consumer(64, move |line: String| {
+///     text_view.append(&line); // on the main thread
+/// });
+/// console.send(line).await?;
+/// ```
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+#[track_caller]
+pub fn main_consumer<T, F>(capacity: usize, mut f: F) -> MainConsumer<T>
+where
+    T: Send + 'static,
+    F: FnMut(T) + Send + 'static,
+{
+    let location = Location::caller();
+    let (tx, mut rx) = mpsc::channel(capacity);
+
+    // Detached: the consumer's lifetime is tied to its channel, not a handle.
+    drop(spawn_main(move || async move {
+        while let Some(value) = rx.recv().await {
+            if catch(|| f(value), location).is_err() {
+                break;
+            }
+        }
+    }));
+
+    MainConsumer { tx }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MainSender<u32>>();
+        assert_send_sync::<MainReceiver<u32>>();
+        assert_send_sync::<MainConsumer<u32>>();
+    }
+
+    #[tokio::test]
+    async fn main_channel_applies_backpressure_and_closes() {
+        let (tx, mut rx) = main_channel(1);
+        tx.try_send(1).unwrap();
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+
+        let other = tx.clone();
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(1));
+        other.try_send(3).unwrap();
+        drop(other);
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn closed_receiver_rejects_sends() {
+        let (tx, mut rx) = main_channel(4);
+        rx.close();
+        assert!(tx.is_closed());
+        assert_eq!(tx.try_send(1), Err(TrySendError::Closed(1)));
+    }
+
+    #[tokio::test]
+    async fn main_receiver_is_a_stream() {
+        let (tx, mut rx) = main_channel(4);
+        tx.try_send("a").unwrap();
+        drop(tx);
+
+        let mut rx = Pin::new(&mut rx);
+        let first = std::future::poll_fn(|cx| rx.as_mut().poll_next(cx)).await;
+        let end = std::future::poll_fn(|cx| rx.as_mut().poll_next(cx)).await;
+        assert_eq!((first, end), (Some("a"), None));
+    }
+
+    // On macOS, or with an emulated main thread, these need a running main
+    // loop; see harness_integration.rs.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use super::super::*;
+        use crate::executor::on_home_thread;
+
+        #[tokio::test]
+        async fn main_consumer_handles_values_in_order_on_home_thread() {
+            let (tx, mut rx) = main_channel(8);
+            let consumer = main_consumer(2, move |value: u32| {
+                tx.try_send((value, on_home_thread())).unwrap();
+            });
+            for value in 0..5 {
+                consumer.send(value).await.unwrap();
+            }
+            drop(consumer);
+
+            let mut received = Vec::new();
+            while let Some(item) = rx.recv().await {
+                received.push(item);
+            }
+            assert_eq!(
+                received,
+                (0..5).map(|value| (value, true)).collect::<Vec<_>>()
+            );
+        }
+
+        #[tokio::test]
+        async fn panicking_consumer_stops_and_closes() {
+            let consumer = main_consumer(1, |_: u32| panic!("boom"));
+            consumer.send(1).await.unwrap();
+            consumer.closed().await;
+            assert!(matches!(consumer.send(2).await, Err(SendError(2))));
+        }
+    }
+}
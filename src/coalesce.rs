@@ -0,0 +1,358 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::mem;
+use std::panic::Location;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::Poll;
+
+use tokio::sync::oneshot;
+
+use crate::capacity::{Permit, MAIN_QUEUE};
+use crate::context::CallerContext;
+use crate::dispatch::catch;
+use crate::error::{DispatchError, MainPanic};
+use crate::main_loop::{self, Watchdog};
+use crate::metrics::{Probe, Target};
+
+type Waiter<R> = oneshot::Sender<Result<R, DispatchError>>;
+
+/// A queued coalesced job and everyone waiting for its result.
+struct Pending<R> {
+    f: Option<Box<dyn FnOnce() -> R + Send>>,
+    // Captured along with `f`, and replaced with it.
+    context: CallerContext,
+    location: &'static Location<'static>,
+    waiters: Vec<Waiter<R>>,
+    // Whether the job was handed to the main thread, or run inline.
+    queued: bool,
+}
+
+type Slot<R> = Arc<Mutex<Pending<R>>>;
+
+/// Queued jobs per key, in one map per `(K, R)` pair.
+///
+/// A job leaves the registry as soon as the main thread starts it, so later
+/// calls with the same key queue a new job instead of joining one whose
+/// closure is already running.
+fn registry() -> &'static Mutex<HashMap<TypeId, Box<dyn Any + Send>>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Runs the latest closure queued in `slot`, if it has not run yet, and sends
+/// its result to every waiter.
+fn run<K, R>(slot: &Slot<R>)
+where
+    K: Hash + Eq + Send + 'static,
+    R: Clone + Send + 'static,
+{
+    {
+        let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(jobs) = registry
+            .get_mut(&TypeId::of::<(K, R)>())
+            .and_then(|jobs| jobs.downcast_mut::<HashMap<K, Slot<R>>>())
+        {
+            jobs.retain(|_, queued| !Arc::ptr_eq(queued, slot));
+        }
+    }
+
+    let (f, context, location, mut waiters) = {
+        let mut pending = slot.lock().unwrap_or_else(|e| e.into_inner());
+        pending.queued = true;
+        let Some(f) = pending.f.take() else {
+            return;
+        };
+        let context = mem::replace(&mut pending.context, CallerContext::empty());
+        (
+            f,
+            context,
+            pending.location,
+            mem::take(&mut pending.waiters),
+        )
+    };
+
+    // The newest waiter is the one whose closure actually ran.
+    let Some(latest) = waiters.pop() else {
+        return;
+    };
+    match catch(|| context.scope(f), location) {
+        Ok(value) => {
+            for waiter in waiters {
+                let _ = waiter.send(Ok(value.clone()));
+            }
+            let _ = latest.send(Ok(value));
+        }
+        Err(DispatchError::Panicked(panic)) => {
+            // Only one waiter can own the original payload; the rest get a
+            // copy of its message.
+            let message = panic.message().unwrap_or("Box<dyn Any>").to_string();
+            for waiter in waiters {
+                let copy = MainPanic::new(Box::new(message.clone()), location);
+                let _ = waiter.send(Err(DispatchError::Panicked(copy)));
+            }
+            let _ = latest.send(Err(DispatchError::Panicked(panic)));
+        }
+        Err(_) => {}
+    }
+}
+
+/// Hands the job in `slot` to the main thread, unless that already happened.
+/// The job holds `permit` until it has run.
+fn queue<K, R>(slot: &Slot<R>, permit: Permit)
+where
+    K: Hash + Eq + Send + 'static,
+    R: Clone + Send + 'static,
+{
+    let mut pending = slot.lock().unwrap_or_else(|e| e.into_inner());
+    if mem::replace(&mut pending.queued, true) {
+        return;
+    }
+    drop(pending);
+
+    let slot = Arc::clone(slot);
+    let probe = Probe::queued(Target::Main(None));
+    crate::dispatch::submit(
+        crate::dispatch::Priority::Normal,
+        Box::new(move || {
+            let _permit = permit;
+            probe.run(|| run::<K, R>(&slot))
+        }),
+    );
+}
+
+/// Dispatches `f` to the main thread, coalescing it with any job queued under
+/// the same `key` that has not started yet.
+///
+/// When a job with `key` is already queued, `f` replaces its closure instead
+/// of being queued again, and every caller waiting on that key receives the
+/// result of the one closure that runs: the most recently submitted one, in
+/// the [context](crate::context) captured by its call. This suits requests
+/// where only the latest state matters, such as UI refreshes during a burst of
+/// updates.
+///
+/// Keys are scoped by their type and by `R`. Dropping the returned future does
+/// not cancel the job, since other callers may be waiting on it. A panic in
+/// the closure is resumed in every waiter, and like [`on_main`](crate::on_main)
+/// the future panics with [`DispatchError::NotRunning`] if no main loop starts
+/// within the [grace period](crate::set_main_loop_grace_period).
+///
+/// ```ignore
+/// // A burst of these runs `refresh` once, with the latest snapshot.
+/// apple_main::on_main_coalesced("sidebar", move || sidebar.refresh(snapshot)).await;
+/// ```
+#[track_caller]
+pub fn on_main_coalesced<K, F, R>(key: K, f: F) -> impl Future<Output = R> + Send
+where
+    K: Hash + Eq + Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+    R: Clone + Send + 'static,
+{
+    let location = Location::caller();
+    let context = CallerContext::capture();
+    let (tx, mut rx) = oneshot::channel();
+    let watchdog = Watchdog::start();
+
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let jobs = registry
+        .entry(TypeId::of::<(K, R)>())
+        .or_insert_with(|| Box::new(HashMap::<K, Slot<R>>::new()))
+        .downcast_mut::<HashMap<K, Slot<R>>>()
+        .expect("coalesced jobs are keyed by their own type");
+
+    let slot = match jobs.get(&key) {
+        Some(slot) => {
+            let mut pending = slot.lock().unwrap_or_else(|e| e.into_inner());
+            pending.f = Some(Box::new(f));
+            pending.context = context;
+            pending.location = location;
+            pending.waiters.push(tx);
+            Arc::clone(slot)
+        }
+        None => {
+            let slot = Arc::new(Mutex::new(Pending {
+                f: Some(Box::new(f)),
+                context,
+                location,
+                waiters: vec![tx],
+                queued: false,
+            }));
+            jobs.insert(key, Arc::clone(&slot));
+
+            // On the main thread there is no queue to wait on, so the job runs
+            // inline when a waiter is first polled, like `on_main`. Elsewhere,
+            // a full main queue leaves it for the waiters to queue once there
+            // is room.
+            if !crate::is_main_thread() {
+                if let Some(permit) = MAIN_QUEUE.try_acquire() {
+                    queue::<K, R>(&slot, permit);
+                }
+            }
+            slot
+        }
+    };
+    drop(registry);
+
+    async move {
+        // The future may be polled on another thread than it was created on,
+        // and the closure must only run inline on the main thread.
+        let mut slot = Some(slot);
+        if crate::is_main_thread() {
+            let slot = slot.take().expect("set above");
+            if !slot.lock().unwrap_or_else(|e| e.into_inner()).queued {
+                Probe::inline(Target::Main(None)).run(|| run::<K, R>(&slot));
+            }
+        }
+
+        // A job run inline has already answered, so only queued ones can be
+        // stranded without a main loop.
+        let mut watchdog = watchdog.filter(|_| !crate::is_main_thread());
+        let mut acquire: Option<Pin<Box<dyn Future<Output = Permit> + Send>>> = None;
+        let result = std::future::poll_fn(|cx| {
+            if let Poll::Ready(result) = Pin::new(&mut rx).poll(cx) {
+                return Poll::Ready(result.unwrap_or(Err(DispatchError::ShuttingDown)));
+            }
+            // Until some waiter has queued the job, wait for room to queue it.
+            if let Some(unqueued) = slot.take() {
+                if !unqueued.lock().unwrap_or_else(|e| e.into_inner()).queued {
+                    let acquire = acquire.get_or_insert_with(|| Box::pin(MAIN_QUEUE.acquire()));
+                    match acquire.as_mut().poll(cx) {
+                        Poll::Ready(permit) => queue::<K, R>(&unqueued, permit),
+                        Poll::Pending => slot = Some(unqueued),
+                    }
+                }
+            }
+            if watchdog
+                .as_mut()
+                .is_some_and(|watchdog| watchdog.poll_expired(cx).is_ready())
+            {
+                watchdog = None;
+                if !main_loop::is_main_loop_running() {
+                    return Poll::Ready(Err(DispatchError::NotRunning));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        result.unwrap_or_else(|e| e.raise())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesced_future_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&on_main_coalesced("send", || 1));
+    }
+
+    // Never registers a main loop: other tests rely on none running.
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    mod without_main_loop {
+        use super::super::*;
+        use std::time::Duration;
+
+        #[tokio::test]
+        async fn coalesced_call_fails_without_main_loop() {
+            crate::set_main_loop_grace_period(Duration::from_millis(10));
+            let call = tokio::spawn(on_main_coalesced("no-main-loop", || 1));
+            let payload = call.await.unwrap_err().into_panic();
+            let message = payload
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_default();
+            assert!(message.contains("no main loop is running"), "{message}");
+        }
+    }
+
+    // On macOS, or with an emulated main thread, these need a running main
+    // loop; see harness_integration.rs.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use super::super::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[tokio::test]
+        async fn queued_jobs_with_same_key_run_once_with_latest_closure() {
+            let runs = Arc::new(AtomicUsize::new(0));
+            let calls: Vec<_> = (1..=3)
+                .map(|value| {
+                    let runs = Arc::clone(&runs);
+                    on_main_coalesced("same-key", move || {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        value
+                    })
+                })
+                .collect();
+
+            let mut results = Vec::new();
+            for call in calls {
+                results.push(call.await);
+            }
+            assert_eq!(results, vec![3, 3, 3]);
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+        }
+
+        #[tokio::test]
+        async fn different_keys_are_not_coalesced() {
+            let a = on_main_coalesced(("distinct", 1), || 1);
+            let b = on_main_coalesced(("distinct", 2), || 2);
+            assert_eq!((a.await, b.await), (1, 2));
+        }
+
+        #[tokio::test]
+        async fn same_key_with_different_result_types_is_not_coalesced() {
+            let a = on_main_coalesced("typed", || 1u32);
+            let b = on_main_coalesced("typed", || "two");
+            assert_eq!((a.await, b.await), (1, "two"));
+        }
+
+        #[tokio::test]
+        async fn started_job_is_not_joined_by_later_calls() {
+            assert_eq!(on_main_coalesced("sequential", || 1).await, 1);
+            assert_eq!(on_main_coalesced("sequential", || 2).await, 2);
+        }
+
+        #[tokio::test]
+        async fn closure_runs_in_caller_context() {
+            tokio::task_local! {
+                static REQUEST_ID: u64;
+            }
+            crate::context::propagate_task_local(&REQUEST_ID);
+
+            // Awaited outside the scope the call was made in.
+            let call =
+                REQUEST_ID.sync_scope(7, || on_main_coalesced("context", || REQUEST_ID.get()));
+            assert_eq!(call.await, 7);
+        }
+
+        #[tokio::test]
+        async fn coalesced_job_is_recorded_in_metrics() {
+            let before = crate::metrics().total.run.count();
+            let a = on_main_coalesced("metrics", || 1);
+            let b = on_main_coalesced("metrics", || 2);
+            assert_eq!((a.await, b.await), (2, 2));
+            // Other tests dispatch concurrently, so only a lower bound holds.
+            assert!(crate::metrics().total.run.count() > before);
+        }
+
+        #[tokio::test]
+        async fn panic_is_resumed_in_every_waiter() {
+            let first = tokio::spawn(on_main_coalesced("panics", || -> u32 { panic!("boom") }));
+            let second = tokio::spawn(on_main_coalesced("panics", || -> u32 { panic!("boom") }));
+
+            for handle in [first, second] {
+                let payload = handle.await.unwrap_err().into_panic();
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned());
+                assert_eq!(message.as_deref(), Some("boom"));
+            }
+        }
+    }
+}
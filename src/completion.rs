@@ -0,0 +1,181 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use tokio::sync::oneshot;
+
+use crate::dispatch::try_on_main;
+use crate::error::DispatchError;
+
+/// The calling side of a [`completion`] pair, to be handed to a
+/// callback-style API as its completion handler.
+///
+/// The completer is `Send + Sync` and can be called from any thread, including
+/// from `Fn` callbacks, since it only needs `&self`. Only the first call
+/// delivers a value. Dropping it without calling it resolves the
+/// [`CompletionFuture`] with [`DispatchError::NeverCompleted`].
+pub struct Completer<T> {
+    tx: Mutex<Option<oneshot::Sender<T>>>,
+}
+
+impl<T> Completer<T> {
+    /// Delivers `value` to the [`CompletionFuture`].
+    ///
+    /// Returns `false`, dropping `value`, if the completer was already called
+    /// or the future was dropped.
+    pub fn complete(&self, value: T) -> bool {
+        let tx = self.tx.lock().unwrap_or_else(|e| e.into_inner()).take();
+        tx.is_some_and(|tx| tx.send(value).is_ok())
+    }
+
+    /// Whether the completer has already been called.
+    pub fn is_completed(&self) -> bool {
+        self.tx.lock().unwrap_or_else(|e| e.into_inner()).is_none()
+    }
+}
+
+impl<T> fmt::Debug for Completer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Completer")
+            .field("completed", &self.is_completed())
+            .finish()
+    }
+}
+
+/// The awaiting side of a [`completion`] pair.
+///
+/// Resolves with the value passed to [`Completer::complete`], or with
+/// [`DispatchError::NeverCompleted`] if the completer was dropped first.
+#[must_use = "futures do nothing unless awaited"]
+pub struct CompletionFuture<T> {
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> Future for CompletionFuture<T> {
+    type Output = Result<T, DispatchError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|result| result.map_err(|_| DispatchError::NeverCompleted))
+    }
+}
+
+impl<T> fmt::Debug for CompletionFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompletionFuture").finish_non_exhaustive()
+    }
+}
+
+/// Creates a [`Completer`] for a callback-style API and the
+/// [`CompletionFuture`] that resolves when it is called.
+///
+/// ```ignore
+/// let (done, started) = apple_main::completion();
+/// vm.start_with_completion_handler(move |error| {
+///     done.complete(error); // on the VM's dispatch queue
+/// });
+/// started.await?;
+/// ```
+pub fn completion<T>() -> (Completer<T>, CompletionFuture<T>) {
+    let (tx, rx) = oneshot::channel();
+    (
+        Completer {
+            tx: Mutex::new(Some(tx)),
+        },
+        CompletionFuture { rx },
+    )
+}
+
+/// Runs `f` on the main thread with a [`Completer`], then waits for the
+/// completer to be called.
+///
+/// This is the usual shape of Apple's asynchronous APIs: start an operation on
+/// the main thread and get the result in a completion handler, possibly on
+/// another queue.
+///
+/// ```ignore
+/// let result = apple_main::call_on_main_with_completion(move |done| {
+///     vm.start_with_completion_handler(move |error| done.complete(error));
+/// })
+/// .await?;
+/// ```
+///
+/// Fails with [`DispatchError::Panicked`] if `f` panics, and with
+/// [`DispatchError::NeverCompleted`] if the completer is dropped without being
+/// called.
+#[track_caller]
+pub fn call_on_main_with_completion<T, F>(
+    f: F,
+) -> impl Future<Output = Result<T, DispatchError>> + Send
+where
+    T: Send + 'static,
+    F: FnOnce(Completer<T>) + Send + 'static,
+{
+    let (completer, future) = completion();
+    let started = try_on_main(move || f(completer));
+
+    async move {
+        started.await?;
+        future.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion_handles_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Completer<u32>>();
+        assert_send_sync::<CompletionFuture<u32>>();
+    }
+
+    #[tokio::test]
+    async fn completer_called_from_another_thread_resolves_future() {
+        let (done, future) = completion();
+        std::thread::spawn(move || assert!(done.complete(7)));
+        assert_eq!(future.await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn only_the_first_call_is_delivered() {
+        let (done, future) = completion();
+        assert!(done.complete(1));
+        assert!(done.is_completed());
+        assert!(!done.complete(2));
+        assert_eq!(future.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_completer_yields_never_completed() {
+        let (done, future) = completion::<u32>();
+        drop(done);
+        assert!(matches!(future.await, Err(DispatchError::NeverCompleted)));
+    }
+
+    // On macOS, or with an emulated main thread, these need a running main
+    // loop; see harness_integration.rs.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use super::super::*;
+
+        #[tokio::test]
+        async fn call_on_main_with_completion_awaits_handler() {
+            let result = call_on_main_with_completion(|done| {
+                std::thread::spawn(move || done.complete("started"));
+            })
+            .await;
+            assert_eq!(result.unwrap(), "started");
+        }
+
+        #[tokio::test]
+        async fn call_on_main_with_completion_reports_panic() {
+            let result = call_on_main_with_completion::<(), _>(|_| panic!("boom")).await;
+            assert!(matches!(result, Err(DispatchError::Panicked(_))));
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Carries task-local context from the dispatch site into main-thread closures.
+//!
+//! Closures queued on the main thread run outside the task that dispatched
+//! them, so `tokio::task_local!` values such as request IDs are not visible
+//! there. A registered propagator captures its value when
+//! [`on_main`](crate::on_main) or [`on_main_sync`](crate::on_main_sync) is
+//! called, and re-establishes it around the closure on the main thread:
+//!
+//! ```ignore
+//! tokio::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! apple_main::context::propagate_task_local(&REQUEST_ID);
+//!
+//! REQUEST_ID
+//!     .scope(7, async {
+//!         apple_main::on_main(|| assert_eq!(REQUEST_ID.get(), 7)).await;
+//!     })
+//!     .await;
+//! ```
+//!
+//! Closures that run inline, on the main thread or on non-macOS platforms
+//! without the `emulated-main-thread` feature, already run in the caller's
+//! context and see the same values.
+
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+
+use tokio::task::LocalKey;
+
+type Capture = Box<dyn Fn() -> Option<Box<dyn Captured>> + Send + Sync>;
+
+static PROPAGATORS: RwLock<Vec<Capture>> = RwLock::new(Vec::new());
+
+/// Addresses of the task-locals passed to [`propagate_task_local`].
+static TASK_LOCALS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// A value captured at the dispatch site, ready to be re-established.
+trait Captured: Send {
+    fn scope(self: Box<Self>, f: &mut dyn FnMut());
+}
+
+struct Scoped<T, S> {
+    value: T,
+    scope: Arc<S>,
+}
+
+impl<T, S> Captured for Scoped<T, S>
+where
+    T: Send,
+    S: Fn(T, &mut dyn FnMut()) + Send + Sync,
+{
+    fn scope(self: Box<Self>, f: &mut dyn FnMut()) {
+        (self.scope)(self.value, f)
+    }
+}
+
+/// Registers a propagator for closures dispatched to the main thread.
+///
+/// `capture` runs at every dispatch site and returns the value to carry, or
+/// `None` if there is nothing to propagate. `scope` runs on the main thread
+/// and must call the closure it is given exactly once, with the value in
+/// effect. Propagators are process-wide and cannot be unregistered.
+///
+/// [`propagate_task_local`] covers the common case; use this for other
+/// kinds of context, such as a thread-local set for the closure's duration.
+pub fn register<T, C, S>(capture: C, scope: S)
+where
+    T: Send + 'static,
+    C: Fn() -> Option<T> + Send + Sync + 'static,
+    S: Fn(T, &mut dyn FnMut()) + Send + Sync + 'static,
+{
+    let scope = Arc::new(scope);
+    let capture: Capture = Box::new(move || {
+        let value = capture()?;
+        Some(Box::new(Scoped {
+            value,
+            scope: Arc::clone(&scope),
+        }))
+    });
+    PROPAGATORS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(capture);
+}
+
+/// Propagates a `tokio::task_local!` value into main-thread closures.
+///
+/// The value is cloned at the dispatch site, if it is set there, and the
+/// closure runs inside [`LocalKey::sync_scope`] with the clone. Registering
+/// the same key again has no effect.
+pub fn propagate_task_local<T>(key: &'static LocalKey<T>)
+where
+    T: Clone + Send + 'static,
+{
+    let address = key as *const LocalKey<T> as usize;
+    let mut registered = TASK_LOCALS.lock().unwrap_or_else(|e| e.into_inner());
+    if registered.contains(&address) {
+        return;
+    }
+    registered.push(address);
+
+    register(
+        move || key.try_with(T::clone).ok(),
+        move |value, f| key.sync_scope(value, f),
+    );
+}
+
+/// The context captured at a dispatch site.
+pub(crate) struct CallerContext(Vec<Box<dyn Captured>>);
+
+impl CallerContext {
+    /// Captures the values of every registered propagator.
+    pub(crate) fn capture() -> Self {
+        let propagators = PROPAGATORS.read().unwrap_or_else(|e| e.into_inner());
+        Self(propagators.iter().filter_map(|capture| capture()).collect())
+    }
+
+    /// A context with no values, whose [`scope`](Self::scope) just runs `f`.
+    pub(crate) fn empty() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Runs `f` with the captured values re-established.
+    pub(crate) fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        if self.0.is_empty() {
+            return f();
+        }
+
+        let mut f = Some(f);
+        let mut result = None;
+        enter(self.0, &mut || result = f.take().map(|f| f()));
+        result.expect("context propagator did not run the dispatched closure")
+    }
+}
+
+fn enter(mut values: Vec<Box<dyn Captured>>, f: &mut dyn FnMut()) {
+    match values.pop() {
+        None => f(),
+        Some(value) => value.scope(&mut || enter(mem::take(&mut values), f)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    tokio::task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+    }
+
+    #[tokio::test]
+    async fn captured_task_locals_are_restored_elsewhere() {
+        propagate_task_local(&REQUEST_ID);
+        propagate_task_local(&TENANT);
+
+        let context = REQUEST_ID
+            .scope(7, TENANT.scope("acme", async { CallerContext::capture() }))
+            .await;
+
+        assert!(REQUEST_ID.try_with(|_| ()).is_err());
+        let seen = context.scope(|| (REQUEST_ID.get(), TENANT.get()));
+        assert_eq!(seen, (7, "acme"));
+    }
+
+    #[tokio::test]
+    async fn task_local_is_propagated_once() {
+        tokio::task_local! {
+            static ONCE: u64;
+        }
+        propagate_task_local(&ONCE);
+        propagate_task_local(&ONCE);
+
+        // Other tests' task-locals are unset here, so only ONCE is captured.
+        let context = ONCE.scope(1, async { CallerContext::capture() }).await;
+        assert_eq!(context.0.len(), 1);
+    }
+
+    #[test]
+    fn unset_task_locals_are_not_captured() {
+        propagate_task_local(&REQUEST_ID);
+
+        let set = CallerContext::capture().scope(|| REQUEST_ID.try_with(|_| ()).is_ok());
+        assert!(!set);
+    }
+}
@@ -1,54 +1,1071 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::capacity::{Permit, MAIN_QUEUE};
+use crate::context::CallerContext;
+use crate::error::{DispatchError, MainPanic};
+use crate::main_loop::{self, Watchdog};
+use crate::main_thread::MainThread;
+use crate::metrics::{Probe, Target};
+
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+const PENDING: u8 = 0;
+const RUNNING: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Lifecycle shared between a [`TryMainTask`] and the job it dispatched.
+struct TaskState(AtomicU8);
+
+impl TaskState {
+    fn new() -> Arc<Self> {
+        Arc::new(Self(AtomicU8::new(PENDING)))
+    }
+
+    /// Claims the task for execution. Returns `false` if it was cancelled first.
+    fn start(&self) -> bool {
+        self.0
+            .compare_exchange(PENDING, RUNNING, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Cancels the task if the main thread has not picked it up yet.
+    ///
+    /// Returns `false` if the closure had already started.
+    fn cancel(&self) -> bool {
+        self.0
+            .compare_exchange(PENDING, CANCELLED, Ordering::AcqRel, Ordering::Acquire)
+            .map_or_else(|state| state == CANCELLED, |_| true)
+    }
+
+    /// Gives up on the task, cancelling it if it has not started.
+    fn time_out(&self) -> DispatchError {
+        DispatchError::TimedOut {
+            started: !self.cancel(),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire) == CANCELLED
+    }
+}
+
+/// Runs `f`, converting a panic into [`DispatchError::Panicked`].
+pub(crate) fn catch<F, R>(f: F, location: &'static Location<'static>) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R,
+{
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| DispatchError::Panicked(MainPanic::new(payload, location)))
+}
+
+/// How urgently a closure dispatched to the main thread should run.
+///
+/// Queued work runs highest priority first, and in FIFO order within a
+/// priority. To keep a steady stream of urgent work from starving the rest,
+/// a lower-priority job that has been passed over [`STARVATION_LIMIT`] times
+/// in a row runs next regardless.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// How many times queued work can be passed over for higher-priority work
+/// before it is run anyway.
+pub const STARVATION_LIMIT: u32 = 16;
+
+const LEVELS: usize = 3;
+
+// Only driven by a main queue on macOS or with an emulated main thread;
+// otherwise work always runs inline, but the scheduler is still unit-tested.
+#[cfg_attr(
+    not(any(target_os = "macos", feature = "emulated-main-thread")),
+    allow(dead_code)
+)]
+struct Level {
+    jobs: VecDeque<Job>,
+    passed_over: u32,
+}
+
+impl Level {
+    const fn new() -> Self {
+        Self {
+            jobs: VecDeque::new(),
+            passed_over: 0,
+        }
+    }
+}
+
+/// Priority-aware queue of main-thread jobs.
+///
+/// Every push is paired with one wake-up of the main queue, which runs
+/// whichever job [`Scheduler::pop`] picks; the main queue itself stays FIFO.
+#[cfg_attr(
+    not(any(target_os = "macos", feature = "emulated-main-thread")),
+    allow(dead_code)
+)]
+struct Scheduler {
+    levels: Mutex<[Level; LEVELS]>,
+}
+
+#[cfg_attr(
+    not(any(target_os = "macos", feature = "emulated-main-thread")),
+    allow(dead_code)
+)]
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            levels: Mutex::new([Level::new(), Level::new(), Level::new()]),
+        }
+    }
+
+    fn push(&self, priority: Priority, job: Job) {
+        self.levels.lock().unwrap_or_else(|e| e.into_inner())[priority as usize]
+            .jobs
+            .push_back(job);
+    }
+
+    fn pop(&self) -> Option<Job> {
+        let mut levels = self.levels.lock().unwrap_or_else(|e| e.into_inner());
+
+        let highest = (0..LEVELS).rev().find(|&i| !levels[i].jobs.is_empty())?;
+        let chosen = (0..highest)
+            .rev()
+            .find(|&i| !levels[i].jobs.is_empty() && levels[i].passed_over >= STARVATION_LIMIT)
+            .unwrap_or(highest);
+
+        for level in &mut levels[..chosen] {
+            if !level.jobs.is_empty() {
+                level.passed_over += 1;
+            }
+        }
+        levels[chosen].passed_over = 0;
+        levels[chosen].jobs.pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        let levels = self.levels.lock().unwrap_or_else(|e| e.into_inner());
+        levels.iter().all(|level| level.jobs.is_empty())
+    }
+
+    /// Runs the next job, if any, on the current thread.
+    fn run_next(&self) {
+        if let Some(job) = self.pop() {
+            job();
+        }
+    }
+}
+
+#[cfg_attr(
+    not(any(target_os = "macos", feature = "emulated-main-thread")),
+    allow(dead_code)
+)]
+static SCHEDULER: Scheduler = Scheduler::new();
+
+/// Whether closures are waiting in the main-thread scheduler.
+pub(crate) fn has_queued_jobs() -> bool {
+    !SCHEDULER.is_empty()
+}
+
+/// Queues `job` on the main thread at `priority`.
+///
+/// Callers run work inline instead when [`is_main_thread`](crate::is_main_thread)
+/// is true, so apart from the executor's own jobs this is only reached from
+/// other threads.
 #[cfg(target_os = "macos")]
-pub async fn on_main<F, R>(f: F) -> R
+pub(crate) fn submit(priority: Priority, job: Job) {
+    SCHEDULER.push(priority, job);
+    dispatch::Queue::main().exec_async(|| SCHEDULER.run_next());
+}
+
+#[cfg(all(not(target_os = "macos"), feature = "emulated-main-thread"))]
+pub(crate) fn submit(priority: Priority, job: Job) {
+    SCHEDULER.push(priority, job);
+    crate::platform::other::main_loop().enqueue(Box::new(|| SCHEDULER.run_next()));
+}
+
+#[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+pub(crate) fn submit(_priority: Priority, _job: Job) {
+    unreachable!("every thread counts as the main thread without a main queue");
+}
+
+/// A handle to a closure dispatched to the main thread by [`try_on_main`].
+///
+/// Awaiting the handle yields the closure's return value, or a
+/// [`DispatchError`] describing why there is none. If the handle is dropped or
+/// [`abort`](TryMainTask::abort)ed before the main thread picks the closure
+/// up, the closure is skipped entirely. Once it has started running it always
+/// runs to completion; its result is then simply discarded.
+///
+/// If the closure panics, the main thread keeps running and the panic is
+/// returned as [`DispatchError::Panicked`].
+#[must_use = "dropping a TryMainTask cancels the closure if it has not started yet"]
+pub struct TryMainTask<R> {
+    dispatch: Dispatched<R>,
+}
+
+type InlineFn<R> = Box<dyn FnOnce() -> R + Send>;
+type Prepared<R> = Box<dyn FnOnce() -> Result<R, DispatchError> + Send>;
+type Submit<R> = Box<dyn FnOnce(Permit) -> Dispatched<R> + Send>;
+type Requeue<R> =
+    fn(Priority, Option<&'static str>, &'static Location<'static>, InlineFn<R>) -> Dispatched<R>;
+
+enum Dispatched<R> {
+    /// Waiting for room in the main queue before it can be queued.
+    Waiting {
+        acquire: Pin<Box<dyn Future<Output = Permit> + Send + Sync>>,
+        submit: Mutex<Option<Submit<R>>>,
+        watchdog: Option<Watchdog>,
+    },
+    /// Queued on the main thread; the result arrives over `rx`.
+    Queued {
+        rx: oneshot::Receiver<Result<R, DispatchError>>,
+        state: Arc<TaskState>,
+        // Only set if no main loop was running when the task was queued.
+        watchdog: Option<Watchdog>,
+    },
+    /// Created on the main thread, so `f` runs inline when first polled
+    /// there. If polled on another thread, `requeue` queues it instead.
+    Inline {
+        f: Mutex<Option<InlineFn<R>>>,
+        location: &'static Location<'static>,
+        name: Option<&'static str>,
+        priority: Priority,
+        requeue: Requeue<R>,
+        started: bool,
+    },
+}
+
+impl<R> TryMainTask<R> {
+    fn inline<F>(
+        f: F,
+        priority: Priority,
+        location: &'static Location<'static>,
+        name: Option<&'static str>,
+    ) -> Self
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        Self {
+            dispatch: Dispatched::Inline {
+                f: Mutex::new(Some(Box::new(f))),
+                location,
+                name,
+                priority,
+                requeue: queue_on_main::<InlineFn<R>, R>,
+                started: false,
+            },
+        }
+    }
+
+    /// Cancels the closure if it has not started running on the main thread.
+    ///
+    /// Awaiting an aborted task whose closure was skipped yields
+    /// [`DispatchError::Cancelled`].
+    pub fn abort(&self) {
+        match &self.dispatch {
+            Dispatched::Waiting { submit, .. } => {
+                submit.lock().unwrap_or_else(|e| e.into_inner()).take();
+            }
+            Dispatched::Queued { state, .. } => {
+                state.cancel();
+            }
+            Dispatched::Inline { f, .. } => {
+                f.lock().unwrap_or_else(|e| e.into_inner()).take();
+            }
+        }
+    }
+
+    /// Gives up on the task, cancelling the closure if it has not started.
+    fn time_out(&self) -> DispatchError {
+        match &self.dispatch {
+            Dispatched::Waiting { .. } => {
+                self.abort();
+                DispatchError::TimedOut { started: false }
+            }
+            Dispatched::Queued { state, .. } => state.time_out(),
+            Dispatched::Inline { started, .. } => {
+                self.abort();
+                DispatchError::TimedOut { started: *started }
+            }
+        }
+    }
+}
+
+impl<R> Future for TryMainTask<R> {
+    type Output = Result<R, DispatchError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // A task created on the main thread can be handed to another thread,
+        // e.g. through `tokio::spawn`, but its closure must not run there.
+        if let Dispatched::Inline {
+            f,
+            location,
+            name,
+            priority,
+            requeue,
+            ..
+        } = &mut self.dispatch
+        {
+            if !crate::is_main_thread() {
+                if let Some(f) = f.get_mut().unwrap_or_else(|e| e.into_inner()).take() {
+                    self.dispatch = requeue(*priority, *name, location, f);
+                }
+            }
+        }
+
+        if let Dispatched::Waiting {
+            acquire,
+            submit,
+            watchdog,
+        } = &mut self.dispatch
+        {
+            let submit = submit.get_mut().unwrap_or_else(|e| e.into_inner());
+            if submit.is_none() {
+                return Poll::Ready(Err(DispatchError::Cancelled));
+            }
+
+            let Poll::Ready(permit) = acquire.as_mut().poll(cx) else {
+                if watchdog
+                    .as_mut()
+                    .is_some_and(|watchdog| watchdog.poll_expired(cx).is_ready())
+                {
+                    *watchdog = None;
+                    if !main_loop::is_main_loop_running() {
+                        submit.take();
+                        return Poll::Ready(Err(DispatchError::NotRunning));
+                    }
+                }
+                return Poll::Pending;
+            };
+
+            let submit = submit.take().expect("checked above");
+            self.dispatch = submit(permit);
+        }
+
+        match &mut self.dispatch {
+            Dispatched::Waiting { .. } => unreachable!("queued above"),
+            Dispatched::Queued {
+                rx,
+                state,
+                watchdog,
+            } => {
+                if let Poll::Ready(result) = Pin::new(rx).poll(cx) {
+                    return Poll::Ready(match result {
+                        Ok(outcome) => outcome,
+                        Err(_) if state.is_cancelled() => Err(DispatchError::Cancelled),
+                        Err(_) => Err(DispatchError::ShuttingDown),
+                    });
+                }
+
+                if watchdog
+                    .as_mut()
+                    .is_some_and(|watchdog| watchdog.poll_expired(cx).is_ready())
+                {
+                    *watchdog = None;
+                    if !main_loop::is_main_loop_running() && state.cancel() {
+                        return Poll::Ready(Err(DispatchError::NotRunning));
+                    }
+                }
+                Poll::Pending
+            }
+            Dispatched::Inline {
+                f,
+                location,
+                name,
+                started,
+                ..
+            } => match f.get_mut().unwrap_or_else(|e| e.into_inner()).take() {
+                Some(f) => {
+                    *started = true;
+                    Poll::Ready(Probe::inline(Target::Main(*name)).run(|| catch(f, location)))
+                }
+                None if *started => panic!("TryMainTask polled after completion"),
+                None => Poll::Ready(Err(DispatchError::Cancelled)),
+            },
+        }
+    }
+}
+
+impl<R> Drop for TryMainTask<R> {
+    fn drop(&mut self) {
+        if let Dispatched::Queued { state, .. } = &self.dispatch {
+            state.cancel();
+        }
+    }
+}
+
+/// A handle to a closure dispatched to the main thread by [`on_main`], or to
+/// a [`SerialQueue`](crate::SerialQueue) by [`on_queue`](crate::on_queue).
+///
+/// This is a [`TryMainTask`] that panics instead of returning an error: a
+/// panic in the closure is resumed in whichever task awaits the handle, with
+/// its original payload. Cancellation works the same way; awaiting an aborted
+/// task whose closure was skipped panics.
+#[must_use = "dropping a MainTask cancels the closure if it has not started yet"]
+pub struct MainTask<R>(pub(crate) TryMainTask<R>);
+
+impl<R> MainTask<R> {
+    /// Cancels the closure if it has not started running on the main thread.
+    ///
+    /// Awaiting an aborted task whose closure was skipped panics.
+    pub fn abort(&self) {
+        self.0.abort();
+    }
+}
+
+impl<R> Future for MainTask<R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.unwrap_or_else(|e| e.raise()))
+    }
+}
+
+/// Prepares `f` to run on `target`: metrics and the caller's context are
+/// captured now, and re-established around `f` when the returned closure runs.
+pub(crate) fn prepare<F, R>(
+    f: F,
+    location: &'static Location<'static>,
+    target: Target,
+) -> impl FnOnce() -> Result<R, DispatchError> + Send + 'static
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let probe = Probe::queued(target);
+    let context = CallerContext::capture();
+    // A panicking propagator is reported like a panic in `f`, rather than
+    // unwinding through the main loop.
+    move || probe.run(|| catch(|| context.scope(f), location))
+}
+
+/// Creates a job for `f` and a task awaiting its result, for a serial queue or
+/// the idle list. The `watchdog`, if any, fails the task if no main loop runs.
+pub(crate) fn task<F, R>(
+    f: F,
+    location: &'static Location<'static>,
+    target: Target,
+    watchdog: Option<Watchdog>,
+) -> (Job, TryMainTask<R>)
 where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
-    let (tx, rx) = tokio::sync::oneshot::channel();
+    let run = Box::new(prepare(f, location, target));
+    let (job, dispatch) = queued(run, None, watchdog);
+    (job, TryMainTask { dispatch })
+}
 
-    dispatch::Queue::main().exec_async(move || {
-        let result = f();
-        let _ = tx.send(result);
+/// Wraps a prepared closure in a job that reports to the returned state. The
+/// permit, if any, is held until the job has run or been skipped.
+fn queued<R>(
+    run: Prepared<R>,
+    permit: Option<Permit>,
+    watchdog: Option<Watchdog>,
+) -> (Job, Dispatched<R>)
+where
+    R: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let state = TaskState::new();
+
+    let job_state = Arc::clone(&state);
+    let job: Job = Box::new(move || {
+        let _permit = permit;
+        if job_state.start() {
+            let _ = tx.send(run());
+        }
     });
 
-    rx.await.expect(
-        "main thread dispatch failed: the main thread dropped the task before completion. \
-         This likely indicates the main dispatch queue is not running or the process is shutting down.",
-    )
+    let dispatch = Dispatched::Queued {
+        rx,
+        state,
+        watchdog,
+    };
+    (job, dispatch)
 }
 
-#[cfg(not(target_os = "macos"))]
-pub async fn on_main<F, R>(f: F) -> R
+/// Dispatches `f` to the main thread and returns a [`TryMainTask`] for its
+/// result.
+///
+/// The closure is queued immediately, unless the main queue is at its
+/// [limit](crate::set_main_queue_limit): then the task waits for room when
+/// polled. Dropping the returned task before the main thread reaches the
+/// closure cancels it.
+///
+/// When called on the main thread, nothing is queued: the closure runs inline
+/// when the task is first polled, without a round-trip through the main queue.
+/// If the task is first polled on another thread, the closure is queued on the
+/// main thread then.
+#[track_caller]
+pub fn try_on_main<F, R>(f: F) -> TryMainTask<R>
 where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
-    f()
+    try_on_main_with_priority(Priority::Normal, f)
 }
 
-#[cfg(target_os = "macos")]
-pub fn on_main_sync<F, R>(f: F) -> R
+/// Like [`try_on_main`], but queues `f` at the given [`Priority`].
+#[track_caller]
+pub fn try_on_main_with_priority<F, R>(priority: Priority, f: F) -> TryMainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    dispatch_async(priority, None, Location::caller(), f)
+}
+
+fn dispatch_async<F, R>(
+    priority: Priority,
+    name: Option<&'static str>,
+    location: &'static Location<'static>,
+    f: F,
+) -> TryMainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    if crate::is_main_thread() {
+        return TryMainTask::inline(f, priority, location, name);
+    }
+
+    TryMainTask {
+        dispatch: queue_on_main(priority, name, location, f),
+    }
+}
+
+/// Queues `f` on the main thread, or waits for room in the main queue first.
+fn queue_on_main<F, R>(
+    priority: Priority,
+    name: Option<&'static str>,
+    location: &'static Location<'static>,
+    f: F,
+) -> Dispatched<R>
 where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
-    dispatch::Queue::main().exec_sync(f)
+    let run: Prepared<R> = Box::new(prepare(f, location, Target::Main(name)));
+    let queue = move |permit| {
+        let (job, dispatch) = queued(run, Some(permit), Watchdog::start());
+        submit(priority, job);
+        dispatch
+    };
+
+    match MAIN_QUEUE.try_acquire() {
+        Some(permit) => queue(permit),
+        None => Dispatched::Waiting {
+            acquire: Box::pin(MAIN_QUEUE.acquire()),
+            submit: Mutex::new(Some(Box::new(queue))),
+            watchdog: Watchdog::start(),
+        },
+    }
 }
 
-#[cfg(not(target_os = "macos"))]
+/// Like [`try_on_main`], but fails with [`DispatchError::QueueFull`] instead
+/// of waiting when the main queue is at its
+/// [limit](crate::set_main_queue_limit).
+///
+/// ```ignore
+/// match apple_main::try_on_main_now(move || vm.state()) {
+///     Ok(task) => report(task.await?),
+///     Err(DispatchError::QueueFull) => { /* drop this sample */ }
+///     Err(e) => return Err(e),
+/// }
+/// ```
+#[track_caller]
+pub fn try_on_main_now<F, R>(f: F) -> Result<TryMainTask<R>, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let location = Location::caller();
+    if crate::is_main_thread() {
+        return Ok(TryMainTask::inline(f, Priority::Normal, location, None));
+    }
+
+    let permit = MAIN_QUEUE.try_acquire().ok_or(DispatchError::QueueFull)?;
+    let run = Box::new(prepare(f, location, Target::Main(None)));
+    let (job, dispatch) = queued(run, Some(permit), Watchdog::start());
+    submit(Priority::Normal, job);
+    Ok(TryMainTask { dispatch })
+}
+
+/// Dispatches `f` to the main thread and returns a [`MainTask`] for its result.
+///
+/// Like [`try_on_main`], but panics instead of returning a [`DispatchError`].
+#[track_caller]
+pub fn on_main<F, R>(f: F) -> MainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    on_main_with_priority(Priority::Normal, f)
+}
+
+/// Like [`on_main`], but queues `f` at the given [`Priority`].
+///
+/// ```ignore
+/// // Jumps ahead of queued status polling.
+/// apple_main::on_main_with_priority(Priority::High, move || vm.stop()).await;
+/// ```
+#[track_caller]
+pub fn on_main_with_priority<F, R>(priority: Priority, f: F) -> MainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    MainTask(try_on_main_with_priority(priority, f))
+}
+
+/// Like [`on_main`], but records the closure's [metrics](crate::metrics) under
+/// `name` as well as in the totals.
+///
+/// ```ignore
+/// apple_main::on_main_named("vm.start", move || vm.start()).await;
+/// ```
+#[track_caller]
+pub fn on_main_named<F, R>(name: &'static str, f: F) -> MainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    MainTask(dispatch_async(
+        Priority::Normal,
+        Some(name),
+        Location::caller(),
+        f,
+    ))
+}
+
+/// Like [`on_main`], but hands `f` a [`MainThread`] token.
+#[track_caller]
+pub fn on_main_with<F, R>(f: F) -> MainTask<R>
+where
+    F: FnOnce(MainThread) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    on_main(move || f(main_thread_token()))
+}
+
+/// Queues `f` on the main thread and blocks until it returns, or until
+/// `deadline` passes.
+fn run_sync<F, R>(
+    priority: Priority,
+    deadline: Option<Deadline>,
+    location: &'static Location<'static>,
+    name: Option<&'static str>,
+    f: F,
+) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    use std::sync::mpsc::{self, RecvTimeoutError};
+
+    if !main_loop::wait_until_running(deadline.map(|deadline| deadline.instant())) {
+        return Err(match deadline {
+            Some(deadline) if deadline.has_passed() => DispatchError::TimedOut { started: false },
+            _ => DispatchError::NotRunning,
+        });
+    }
+
+    let run = prepare(f, location, Target::Main(name));
+    let Some(permit) = MAIN_QUEUE.acquire_blocking(deadline.map(|deadline| deadline.instant()))
+    else {
+        return Err(DispatchError::TimedOut { started: false });
+    };
+
+    let state = TaskState::new();
+    let (tx, rx) = mpsc::sync_channel(1);
+
+    let job_state = Arc::clone(&state);
+    submit(
+        priority,
+        Box::new(move || {
+            let _permit = permit;
+            if job_state.start() {
+                let _ = tx.send(run());
+            }
+        }),
+    );
+
+    let Some(deadline) = deadline else {
+        return rx.recv().unwrap_or(Err(DispatchError::ShuttingDown));
+    };
+
+    let remaining = deadline.instant().saturating_duration_since(Instant::now());
+    match rx.recv_timeout(remaining) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(state.time_out()),
+        Err(RecvTimeoutError::Disconnected) => Err(DispatchError::ShuttingDown),
+    }
+}
+
+/// Runs `f` on the main thread and blocks until it returns.
+///
+/// A panic in `f` is caught on the main thread and returned as
+/// [`DispatchError::Panicked`].
+///
+/// When called on the main thread, `f` runs inline instead of deadlocking on
+/// the main queue, so helpers can call this without knowing which thread
+/// they are on.
+#[track_caller]
+pub fn try_on_main_sync<F, R>(f: F) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    try_on_main_sync_with_priority(Priority::Normal, f)
+}
+
+/// Like [`try_on_main_sync`], but queues `f` at the given [`Priority`].
+#[track_caller]
+pub fn try_on_main_sync_with_priority<F, R>(priority: Priority, f: F) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    dispatch_sync(priority, None, Location::caller(), f)
+}
+
+fn dispatch_sync<F, R>(
+    priority: Priority,
+    name: Option<&'static str>,
+    location: &'static Location<'static>,
+    f: F,
+) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    if crate::is_main_thread() {
+        return Probe::inline(Target::Main(name)).run(|| catch(f, location));
+    }
+
+    run_sync(priority, None, location, name, f)
+}
+
+/// Runs `f` on the main thread and blocks until it returns.
+///
+/// Like [`try_on_main_sync`], but panics instead of returning a
+/// [`DispatchError`]; a panic in `f` is resumed in the caller.
+#[track_caller]
 pub fn on_main_sync<F, R>(f: F) -> R
 where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
-    f()
+    on_main_sync_with_priority(Priority::Normal, f)
+}
+
+/// Like [`on_main_sync`], but queues `f` at the given [`Priority`].
+#[track_caller]
+pub fn on_main_sync_with_priority<F, R>(priority: Priority, f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    try_on_main_sync_with_priority(priority, f).unwrap_or_else(|e| e.raise())
+}
+
+/// Like [`on_main_sync`], but records the closure's [metrics](crate::metrics)
+/// under `name` as well as in the totals.
+#[track_caller]
+pub fn on_main_sync_named<F, R>(name: &'static str, f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    dispatch_sync(Priority::Normal, Some(name), Location::caller(), f).unwrap_or_else(|e| e.raise())
+}
+
+/// Like [`on_main_sync`], but hands `f` a [`MainThread`] token.
+#[track_caller]
+pub fn on_main_sync_with<F, R>(f: F) -> R
+where
+    F: FnOnce(MainThread) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    on_main_sync(move || f(main_thread_token()))
+}
+
+/// Creates the token handed to `*_with` closures, which only run on the main
+/// thread.
+fn main_thread_token() -> MainThread {
+    // SAFETY: only called from closures dispatched to the main thread.
+    unsafe { MainThread::new_unchecked() }
+}
+
+/// A point in time by which main-thread work must finish.
+///
+/// Created from either an [`Instant`] or a [`Duration`] measured from now, so
+/// the timeout functions accept both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// A deadline at the given instant.
+    pub fn at(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    /// A deadline `timeout` from now.
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    /// The instant this deadline expires.
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    fn has_passed(&self) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
+impl From<Instant> for Deadline {
+    fn from(instant: Instant) -> Self {
+        Self::at(instant)
+    }
+}
+
+impl From<Duration> for Deadline {
+    fn from(timeout: Duration) -> Self {
+        Self::after(timeout)
+    }
+}
+
+/// Dispatches `f` to the main thread, giving up once `deadline` passes.
+///
+/// On timeout this returns [`DispatchError::TimedOut`]. If the closure had not
+/// started yet it is cancelled and never runs; if it had, it keeps running on
+/// the main thread and its result is discarded.
+///
+/// When called on the main thread the closure runs inline and cannot be
+/// interrupted: it only times out if the deadline has already passed when
+/// first polled.
+#[track_caller]
+pub fn on_main_timeout<F, R>(
+    deadline: impl Into<Deadline>,
+    f: F,
+) -> impl Future<Output = Result<R, DispatchError>> + Send
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let deadline = deadline.into();
+    let mut task = try_on_main(f);
+
+    async move {
+        if deadline.has_passed() {
+            return Err(task.time_out());
+        }
+
+        // Kept by a plain thread rather than a tokio timer, so the future
+        // can be polled outside a runtime, as by `spawn_main`.
+        let mut timer = Watchdog::until(deadline.instant());
+        std::future::poll_fn(|cx| {
+            if let Poll::Ready(result) = Pin::new(&mut task).poll(cx) {
+                return Poll::Ready(result);
+            }
+            timer.poll_expired(cx).map(|()| Err(task.time_out()))
+        })
+        .await
+    }
+}
+
+/// Runs `f` on the main thread and blocks until it returns or `deadline`
+/// passes, whichever comes first.
+///
+/// See [`on_main_timeout`] for what happens to the closure on timeout.
+#[track_caller]
+pub fn on_main_sync_timeout<F, R>(deadline: impl Into<Deadline>, f: F) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let deadline = deadline.into();
+    let location = Location::caller();
+    if crate::is_main_thread() {
+        if deadline.has_passed() {
+            return Err(DispatchError::TimedOut { started: false });
+        }
+        return Probe::inline(Target::Main(None)).run(|| catch(f, location));
+    }
+
+    run_sync(Priority::Normal, Some(deadline), location, None, f)
 }
 
 #[cfg(test)]
 mod tests {
-    #[cfg(not(target_os = "macos"))]
+    use super::*;
+
+    tokio::task_local! {
+        static FAULTY: ();
+    }
+
+    #[tokio::test]
+    async fn panicking_propagator_is_reported_as_panic() {
+        crate::context::register(
+            || FAULTY.try_with(|_| ()).ok(),
+            |_, _| panic!("propagator failed"),
+        );
+
+        let run = FAULTY
+            .scope((), async {
+                prepare(|| 1, Location::caller(), Target::Main(None))
+            })
+            .await;
+        assert!(matches!(run(), Err(DispatchError::Panicked(_))));
+    }
+
+    #[test]
+    fn task_state_start_wins_over_later_cancel() {
+        let state = TaskState::new();
+        assert!(state.start());
+        state.cancel();
+        assert!(!state.is_cancelled());
+    }
+
+    #[test]
+    fn task_state_cancel_prevents_start() {
+        let state = TaskState::new();
+        assert!(state.cancel());
+        assert!(!state.start());
+        assert!(state.is_cancelled());
+    }
+
+    #[test]
+    fn task_state_time_out_reports_whether_started() {
+        let pending = TaskState::new();
+        assert!(matches!(
+            pending.time_out(),
+            DispatchError::TimedOut { started: false }
+        ));
+
+        let running = TaskState::new();
+        running.start();
+        assert!(matches!(
+            running.time_out(),
+            DispatchError::TimedOut { started: true }
+        ));
+    }
+
+    fn push_tagged(
+        scheduler: &Scheduler,
+        priority: Priority,
+        tag: u32,
+        log: &Arc<Mutex<Vec<u32>>>,
+    ) {
+        let log = Arc::clone(log);
+        scheduler.push(priority, Box::new(move || log.lock().unwrap().push(tag)));
+    }
+
+    fn drain(scheduler: &Scheduler) {
+        while let Some(job) = scheduler.pop() {
+            job();
+        }
+    }
+
+    #[test]
+    fn scheduler_runs_higher_priority_first() {
+        let scheduler = Scheduler::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        push_tagged(&scheduler, Priority::Low, 1, &log);
+        push_tagged(&scheduler, Priority::Normal, 2, &log);
+        push_tagged(&scheduler, Priority::High, 3, &log);
+        push_tagged(&scheduler, Priority::Normal, 4, &log);
+
+        drain(&scheduler);
+        assert_eq!(*log.lock().unwrap(), vec![3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn scheduler_runs_starved_work_after_limit() {
+        let scheduler = Scheduler::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        push_tagged(&scheduler, Priority::Low, 0, &log);
+        for tag in 1..=STARVATION_LIMIT + 1 {
+            push_tagged(&scheduler, Priority::High, tag, &log);
+        }
+
+        drain(&scheduler);
+        let log = log.lock().unwrap();
+        let low_position = log.iter().position(|&tag| tag == 0).unwrap();
+        assert_eq!(low_position, STARVATION_LIMIT as usize);
+    }
+
+    #[test]
+    fn priority_defaults_to_normal() {
+        assert_eq!(Priority::default(), Priority::Normal);
+        assert!(Priority::High > Priority::Normal && Priority::Normal > Priority::Low);
+    }
+
+    // Elsewhere, a test thread is not the main thread, so the task is queued.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    #[tokio::test]
+    async fn inline_task_runs_when_polled() {
+        let task = TryMainTask::inline(|| 7, Priority::Normal, Location::caller(), None);
+        assert_eq!(task.await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn aborted_inline_task_is_skipped() {
+        let task = TryMainTask::inline(
+            || unreachable!(),
+            Priority::Normal,
+            Location::caller(),
+            None,
+        );
+        task.abort();
+        assert!(matches!(task.await, Err(DispatchError::Cancelled)));
+    }
+
+    #[test]
+    fn inline_task_times_out_before_starting() {
+        let task = TryMainTask::inline(|| 7, Priority::Normal, Location::caller(), None);
+        assert!(matches!(
+            task.time_out(),
+            DispatchError::TimedOut { started: false }
+        ));
+    }
+
+    #[test]
+    fn deadline_from_duration_is_in_the_future() {
+        let deadline = Deadline::from(Duration::from_secs(60));
+        assert!(deadline.instant() > Instant::now());
+        assert!(!deadline.has_passed());
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
     mod non_macos {
-        use crate::{on_main, on_main_sync};
+        use crate::{
+            metrics, on_main, on_main_named, on_main_sync, on_main_sync_named,
+            on_main_sync_timeout, on_main_sync_with, on_main_sync_with_priority, on_main_timeout,
+            on_main_with, on_main_with_priority, try_on_main, try_on_main_sync, DispatchError,
+            Priority,
+        };
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
 
         #[tokio::test]
         async fn on_main_returns_value() {
@@ -56,12 +1073,161 @@ mod tests {
             assert_eq!(result, 42);
         }
 
+        #[tokio::test]
+        async fn on_main_named_records_metrics_under_name() {
+            let before = metrics().total.dispatched;
+            assert_eq!(on_main_named("dispatch.async", || 42).await, 42);
+            assert_eq!(on_main_sync_named("dispatch.sync", || 7), 7);
+
+            let snapshot = metrics();
+            assert!(snapshot.total.dispatched >= before + 2);
+            for name in ["dispatch.async", "dispatch.sync"] {
+                let named = &snapshot.by_name[name];
+                assert_eq!(named.dispatched, 1);
+                assert_eq!(named.queued, 0);
+                assert_eq!(named.wait.sum(), Duration::ZERO);
+                assert_eq!(named.run.count(), 1);
+            }
+        }
+
+        #[tokio::test]
+        async fn try_on_main_now_runs_inline_regardless_of_limit() {
+            crate::set_main_queue_limit(Some(0));
+            let result = crate::try_on_main_now(|| 42);
+            crate::set_main_queue_limit(None);
+            assert_eq!(result.unwrap().await.unwrap(), 42);
+        }
+
         #[tokio::test]
         async fn on_main_executes_closure() {
             let result = on_main(|| String::from("hello")).await;
             assert_eq!(result, "hello");
         }
 
+        #[test]
+        fn on_main_dropped_before_poll_skips_closure() {
+            let ran = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&ran);
+            drop(on_main(move || flag.store(true, Ordering::SeqCst)));
+            assert!(!ran.load(Ordering::SeqCst));
+        }
+
+        #[tokio::test]
+        #[should_panic(expected = "aborted")]
+        async fn on_main_aborted_task_panics_when_awaited() {
+            let task = on_main(|| 42);
+            task.abort();
+            task.await;
+        }
+
+        #[tokio::test]
+        #[should_panic(expected = "boom")]
+        async fn on_main_resumes_panic_in_caller() {
+            on_main(|| panic!("boom")).await;
+        }
+
+        #[tokio::test]
+        async fn on_main_panic_keeps_original_payload() {
+            let result =
+                tokio::spawn(async { on_main(|| std::panic::panic_any(7u32)).await }).await;
+            let payload = result.unwrap_err().into_panic();
+            assert_eq!(payload.downcast_ref::<u32>(), Some(&7));
+        }
+
+        #[tokio::test]
+        async fn try_on_main_returns_value() {
+            assert_eq!(try_on_main(|| 42).await.unwrap(), 42);
+        }
+
+        #[tokio::test]
+        async fn try_on_main_reports_cancellation() {
+            let task = try_on_main(|| 42);
+            task.abort();
+            assert!(matches!(task.await, Err(DispatchError::Cancelled)));
+        }
+
+        #[tokio::test]
+        async fn try_on_main_reports_panic() {
+            let err = try_on_main(|| panic!("boom")).await.unwrap_err();
+            let DispatchError::Panicked(panic) = err else {
+                panic!("expected Panicked, got {err:?}");
+            };
+            assert_eq!(panic.message(), Some("boom"));
+            assert_eq!(panic.location().file(), file!());
+        }
+
+        #[test]
+        fn try_on_main_sync_reports_panic() {
+            let result = try_on_main_sync(|| -> u32 { panic!("boom") });
+            assert!(matches!(result, Err(DispatchError::Panicked(_))));
+        }
+
+        #[tokio::test]
+        async fn on_main_timeout_returns_value_before_deadline() {
+            let result = on_main_timeout(Duration::from_secs(60), || 42).await;
+            assert_eq!(result.unwrap(), 42);
+        }
+
+        #[tokio::test]
+        async fn on_main_timeout_runs_outside_tokio_runtime() {
+            // `spawn_main` futures are polled on a thread without a runtime.
+            let result =
+                crate::spawn_main(|| on_main_timeout(Duration::from_secs(60), || 42)).await;
+            assert_eq!(result.unwrap().unwrap(), 42);
+        }
+
+        #[tokio::test]
+        async fn on_main_timeout_skips_closure_after_deadline() {
+            let ran = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&ran);
+            let result =
+                on_main_timeout(Instant::now(), move || flag.store(true, Ordering::SeqCst)).await;
+            assert!(matches!(
+                result,
+                Err(DispatchError::TimedOut { started: false })
+            ));
+            assert!(!ran.load(Ordering::SeqCst));
+        }
+
+        #[test]
+        fn on_main_sync_timeout_returns_value_before_deadline() {
+            let result = on_main_sync_timeout(Duration::from_secs(60), || 42);
+            assert_eq!(result.unwrap(), 42);
+        }
+
+        #[test]
+        fn on_main_sync_timeout_skips_closure_after_deadline() {
+            let result = on_main_sync_timeout(Duration::ZERO, || 42);
+            assert!(matches!(
+                result,
+                Err(DispatchError::TimedOut { started: false })
+            ));
+        }
+
+        #[tokio::test]
+        async fn on_main_with_passes_token() {
+            let result = on_main_with(|mt| format!("{mt:?}")).await;
+            assert_eq!(result, "MainThread");
+        }
+
+        #[test]
+        fn on_main_sync_with_passes_token() {
+            let result = on_main_sync_with(|_mt| 42);
+            assert_eq!(result, 42);
+        }
+
+        #[tokio::test]
+        async fn on_main_with_priority_returns_value() {
+            assert_eq!(on_main_with_priority(Priority::High, || 1).await, 1);
+            assert_eq!(on_main_sync_with_priority(Priority::Low, || 2), 2);
+        }
+
+        #[test]
+        fn main_task_is_send_and_sync() {
+            fn assert_send_sync<T: Send + Sync>(_: &T) {}
+            assert_send_sync(&on_main(|| 42));
+        }
+
         #[test]
         fn on_main_sync_returns_value() {
             let result = on_main_sync(|| 42);
@@ -75,17 +1241,32 @@ mod tests {
         }
     }
 
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    mod without_main_loop {
+        // NOTE: work dispatched here never runs, because test harnesses don't
+        // run the main loop; see harness_integration.rs for tests that do.
+        // Without a main loop dispatch fails with `NotRunning` once the grace
+        // period passes, instead of blocking forever.
+        use crate::{set_main_loop_grace_period, try_on_main, try_on_main_sync, DispatchError};
+        use std::time::Duration;
+
+        #[tokio::test]
+        async fn dispatch_without_main_loop_fails() {
+            set_main_loop_grace_period(Duration::from_millis(10));
+
+            assert!(matches!(
+                try_on_main_sync(|| 1),
+                Err(DispatchError::NotRunning)
+            ));
+            assert!(matches!(
+                try_on_main(|| 1).await,
+                Err(DispatchError::NotRunning)
+            ));
+        }
+    }
+
     #[cfg(target_os = "macos")]
     mod macos {
-        // NOTE: on_main_sync tests are commented out because they require an active
-        // main dispatch queue, which test harnesses don't provide. The dispatch to
-        // the main queue will block forever waiting for a runloop that isn't running.
-        //
-        // These functions work correctly in actual applications where the main thread
-        // has an active runloop (e.g., GUI apps or apps using CFRunLoop/NSRunLoop).
-        //
-        // To test: use integration tests with a proper main loop setup.
-
         #[test]
         fn module_compiles() {
             // Verify the module compiles with dispatch crate
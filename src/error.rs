@@ -0,0 +1,202 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, Location};
+use std::sync::Mutex;
+
+/// Why a closure dispatched to the main thread did not produce a value.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DispatchError {
+    /// No main loop started within the grace period, so the closure would
+    /// never be picked up.
+    NotRunning,
+    /// The main thread dropped the closure without running it, which happens
+    /// when the process is shutting down.
+    ShuttingDown,
+    /// The closure panicked on the main thread.
+    Panicked(MainPanic),
+    /// The task was aborted before the closure started running.
+    Cancelled,
+    /// The deadline passed before the closure produced a value.
+    ///
+    /// `started` tells whether the closure never got to run (the main thread
+    /// was busy) or started but overran the deadline.
+    TimedOut { started: bool },
+    /// The main-thread actor has stopped and no longer accepts messages.
+    Stopped,
+    /// A completion handler was dropped without being called.
+    NeverCompleted,
+    /// The main queue was at its [limit](crate::set_main_queue_limit), so the
+    /// closure was not queued.
+    QueueFull,
+}
+
+impl DispatchError {
+    /// Panics with this error, resuming the original panic for
+    /// [`DispatchError::Panicked`].
+    ///
+    /// This is what the non-`try_` dispatch functions do with their errors.
+    #[track_caller]
+    pub fn raise(self) -> ! {
+        match self {
+            DispatchError::Panicked(panic) => panic.resume(),
+            other => panic!("{other}"),
+        }
+    }
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::NotRunning => f.write_str(
+                "main thread dispatch failed: no main loop is running to process the task. \
+                 Use #[apple_main::main] for binaries or apple_main::test_main!() for tests, \
+                 or call apple_main::register_main_loop() if the main thread runs its own loop.",
+            ),
+            DispatchError::ShuttingDown => f.write_str(
+                "main thread dispatch failed: the main thread dropped the task before completion. \
+                 This likely indicates the main dispatch queue is not running or the process is shutting down.",
+            ),
+            DispatchError::Panicked(panic) => fmt::Display::fmt(panic, f),
+            DispatchError::Cancelled => {
+                f.write_str("main thread task was aborted before it started running")
+            }
+            DispatchError::TimedOut { started: false } => {
+                f.write_str("main thread task timed out before it started running")
+            }
+            DispatchError::TimedOut { started: true } => {
+                f.write_str("main thread task timed out while running")
+            }
+            DispatchError::Stopped => f.write_str("main thread actor has stopped"),
+            DispatchError::NeverCompleted => {
+                f.write_str("completion handler was dropped without being called")
+            }
+            DispatchError::QueueFull => f.write_str(
+                "main thread dispatch failed: the main queue is at its limit of outstanding tasks",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DispatchError {}
+
+/// A panic caught while running a closure on the main thread.
+pub struct MainPanic {
+    // Only ever accessed by value; the mutex just makes the error `Sync`.
+    payload: Mutex<Box<dyn Any + Send>>,
+    message: Option<String>,
+    location: &'static Location<'static>,
+}
+
+impl MainPanic {
+    pub(crate) fn new(payload: Box<dyn Any + Send>, location: &'static Location<'static>) -> Self {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned());
+
+        Self {
+            payload: Mutex::new(payload),
+            message,
+            location,
+        }
+    }
+
+    /// The panic message, if the payload was a string.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// Where the closure was dispatched from.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// Returns the original panic payload.
+    pub fn into_payload(self) -> Box<dyn Any + Send> {
+        self.payload.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Resumes the panic in the current thread with its original payload.
+    ///
+    /// Nothing is printed, so a caller that catches the panic stays quiet; use
+    /// [`location`](Self::location) or the `Display` output beforehand to
+    /// report where the closure was dispatched from.
+    pub fn resume(self) -> ! {
+        panic::resume_unwind(self.into_payload())
+    }
+}
+
+impl fmt::Debug for MainPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MainPanic")
+            .field("message", &self.message)
+            .field("location", &self.location)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for MainPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "closure dispatched to the main thread at {} panicked",
+            self.location
+        )?;
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch_error_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<DispatchError>();
+    }
+
+    #[test]
+    fn main_panic_extracts_str_message() {
+        let panic = MainPanic::new(Box::new("boom"), Location::caller());
+        assert_eq!(panic.message(), Some("boom"));
+    }
+
+    #[test]
+    fn main_panic_extracts_string_message() {
+        let panic = MainPanic::new(Box::new(String::from("boom")), Location::caller());
+        assert_eq!(panic.message(), Some("boom"));
+    }
+
+    #[test]
+    fn main_panic_keeps_non_string_payload() {
+        let panic = MainPanic::new(Box::new(7u32), Location::caller());
+        assert_eq!(panic.message(), None);
+        assert_eq!(panic.into_payload().downcast_ref::<u32>(), Some(&7));
+    }
+
+    #[test]
+    fn main_panic_display_includes_location_and_message() {
+        let location = Location::caller();
+        let panic = MainPanic::new(Box::new("boom"), location);
+        let display = panic.to_string();
+        assert!(display.contains(&location.to_string()));
+        assert!(display.ends_with(": boom"));
+    }
+
+    #[test]
+    #[should_panic(expected = "aborted")]
+    fn raise_panics_with_error_message() {
+        DispatchError::Cancelled.raise();
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn raise_resumes_original_panic() {
+        DispatchError::Panicked(MainPanic::new(Box::new("boom"), Location::caller())).raise();
+    }
+}
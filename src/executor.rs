@@ -0,0 +1,420 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Instant;
+
+use tokio::sync::oneshot;
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+use crate::capacity::MAIN_QUEUE;
+use crate::dispatch::Job;
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+use crate::dispatch::{submit, Priority};
+use crate::error::{DispatchError, MainPanic};
+
+type LocalFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local! {
+    // Only ever populated on the executor thread, which is the main thread on
+    // macOS. Futures are taken out while being polled so they can spawn or
+    // wake other tasks without re-borrowing the map.
+    static TASKS: RefCell<HashMap<u64, LocalFuture>> = RefCell::new(HashMap::new());
+}
+
+/// Queues `job` on the home thread: the single thread that owns `!Send`
+/// main-thread state, such as the executor's tasks.
+///
+/// This is the main thread on macOS, and with an emulated main thread.
+/// Otherwise `on_main` runs inline on any thread, so `!Send` state lives on a
+/// dedicated background thread instead.
+///
+/// On the main thread, `job` shares the priority queue with `on_main` closures
+/// at normal priority, and counts toward the
+/// [main queue limit](crate::set_main_queue_limit) until it has run. It never
+/// waits for room, since queued closures may be waiting on it.
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+pub(crate) fn schedule(job: Job) {
+    let permit = MAIN_QUEUE.acquire_now();
+    submit(
+        Priority::Normal,
+        Box::new(move || {
+            let _permit = permit;
+            job();
+        }),
+    );
+}
+
+#[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+pub(crate) fn schedule(job: Job) {
+    crate::platform::other::background_loop().enqueue(job);
+}
+
+/// Queues `job` on the home thread, like [`schedule`], once `deadline` has
+/// passed.
+#[cfg(target_os = "macos")]
+pub(crate) fn schedule_at(deadline: Instant, job: Job) {
+    let delay = deadline.saturating_duration_since(Instant::now());
+    dispatch::Queue::main().exec_after(delay, move || schedule(job));
+}
+
+#[cfg(all(not(target_os = "macos"), feature = "emulated-main-thread"))]
+pub(crate) fn schedule_at(deadline: Instant, job: Job) {
+    crate::platform::other::main_loop().enqueue_at(deadline, Box::new(move || schedule(job)));
+}
+
+#[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+pub(crate) fn schedule_at(deadline: Instant, job: Job) {
+    crate::platform::other::background_loop().enqueue_at(deadline, job);
+}
+
+/// Whether the current thread is the home thread used by [`schedule`].
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+pub(crate) fn on_home_thread() -> bool {
+    crate::is_main_thread()
+}
+
+#[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+pub(crate) fn on_home_thread() -> bool {
+    crate::platform::other::is_background_thread()
+}
+
+/// Runs `f` on the home thread and blocks until it returns, running it inline
+/// if already there.
+#[track_caller]
+pub(crate) fn on_home_thread_sync<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    if on_home_thread() {
+        return f();
+    }
+
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    if !crate::main_loop::wait_until_running(None) {
+        DispatchError::NotRunning.raise();
+    }
+
+    let location = Location::caller();
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    schedule(Box::new(move || {
+        let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    }));
+
+    match rx.recv() {
+        Ok(Ok(value)) => value,
+        Ok(Err(payload)) => MainPanic::new(payload, location).resume(),
+        Err(_) => DispatchError::ShuttingDown.raise(),
+    }
+}
+
+/// Wakes a task by queueing a poll of it on the executor thread.
+struct TaskWaker {
+    id: u64,
+    scheduled: AtomicBool,
+    aborted: AtomicBool,
+}
+
+impl TaskWaker {
+    fn schedule(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            let waker = Arc::clone(self);
+            schedule(Box::new(move || poll_task(waker)));
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+/// Polls a task once on the executor thread, dropping it if it finished or
+/// was aborted.
+fn poll_task(waker: Arc<TaskWaker>) {
+    waker.scheduled.store(false, Ordering::Release);
+
+    let Some(mut future) = TASKS.with(|tasks| tasks.borrow_mut().remove(&waker.id)) else {
+        return;
+    };
+    if waker.aborted.load(Ordering::Acquire) {
+        return;
+    }
+
+    let id = waker.id;
+    let std_waker = Waker::from(waker);
+    if future
+        .as_mut()
+        .poll(&mut Context::from_waker(&std_waker))
+        .is_pending()
+    {
+        TASKS.with(|tasks| tasks.borrow_mut().insert(id, future));
+    }
+}
+
+/// Polls the inner future, converting a panic into [`DispatchError::Panicked`].
+struct CatchUnwind<Fut> {
+    future: Pin<Box<Fut>>,
+    location: &'static Location<'static>,
+}
+
+impl<Fut: Future> Future for CatchUnwind<Fut> {
+    type Output = Result<Fut::Output, DispatchError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let location = self.location;
+        let future = self.future.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Ready(value)) => Poll::Ready(Ok(value)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(DispatchError::Panicked(MainPanic::new(
+                payload, location,
+            )))),
+        }
+    }
+}
+
+/// A `Send` handle to a future running on the main thread, returned by
+/// [`spawn_main`].
+///
+/// Awaiting the handle yields the future's output, or a [`DispatchError`] if
+/// it panicked or was aborted. Like a tokio `JoinHandle`, dropping the handle
+/// detaches the task rather than cancelling it.
+pub struct MainJoinHandle<T> {
+    rx: oneshot::Receiver<Result<T, DispatchError>>,
+    waker: Arc<TaskWaker>,
+    abort_on_drop: bool,
+}
+
+impl<T> MainJoinHandle<T> {
+    /// Cancels the task. The future is dropped on the main thread the next
+    /// time the executor gets to it.
+    pub fn abort(&self) {
+        self.waker.aborted.store(true, Ordering::Release);
+        self.waker.schedule();
+    }
+}
+
+impl<T> Future for MainJoinHandle<T> {
+    type Output = Result<T, DispatchError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let aborted = self.waker.aborted.load(Ordering::Acquire);
+        Pin::new(&mut self.rx).poll(cx).map(|result| match result {
+            Ok(outcome) => outcome,
+            Err(_) if aborted => Err(DispatchError::Cancelled),
+            Err(_) => Err(DispatchError::ShuttingDown),
+        })
+    }
+}
+
+impl<T> Drop for MainJoinHandle<T> {
+    fn drop(&mut self) {
+        if self.abort_on_drop {
+            self.abort();
+        }
+    }
+}
+
+/// Runs a `!Send` future on the main thread and returns a `Send` handle to its
+/// output.
+///
+/// The future is created on the main thread by calling `f`, so it may hold
+/// main-thread-only objects across `.await` points. It is driven by the main
+/// run loop: each wake-up queues a poll on the main dispatch queue. On
+/// non-macOS platforms the future runs on a dedicated background thread
+/// instead, unless the main thread is emulated.
+///
+/// If no main loop starts within the
+/// [grace period](crate::set_main_loop_grace_period), `f` is dropped without
+/// being called and the handle yields [`DispatchError::NotRunning`].
+///
+/// ```ignore
+/// let state = apple_main::spawn_main(|| async {
+///     let vm = VZVirtualMachine::new(config); // !Send
+///     vm.start().await;
+///     vm.state()
+/// })
+/// .await?;
+/// ```
+#[track_caller]
+pub fn spawn_main<F, Fut>(f: F) -> MainJoinHandle<Fut::Output>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future + 'static,
+    Fut::Output: Send + 'static,
+{
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let location = Location::caller();
+    let (tx, rx) = oneshot::channel();
+    let waker = Arc::new(TaskWaker {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        scheduled: AtomicBool::new(true),
+        aborted: AtomicBool::new(false),
+    });
+
+    // Taken by whichever comes first: the main thread starting the task, or
+    // the grace period passing without a main loop to start it.
+    let start = Arc::new(Mutex::new(Some((f, tx))));
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    {
+        let start = Arc::clone(&start);
+        crate::main_loop::on_not_running(move || {
+            if let Some((_, tx)) = start.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                let _ = tx.send(Err(DispatchError::NotRunning));
+            }
+        });
+    }
+
+    let task_waker = Arc::clone(&waker);
+    schedule(Box::new(move || {
+        let Some((f, tx)) = start.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+        let future: LocalFuture = Box::pin(async move {
+            let future = CatchUnwind {
+                future: Box::pin(async move { f().await }),
+                location,
+            };
+            let _ = tx.send(future.await);
+        });
+        TASKS.with(|tasks| tasks.borrow_mut().insert(task_waker.id, future));
+        poll_task(task_waker);
+    }));
+
+    MainJoinHandle {
+        rx,
+        waker,
+        abort_on_drop: false,
+    }
+}
+
+/// Runs a `!Send` future on the main thread and awaits its output.
+///
+/// Like [`spawn_main`], but panics instead of returning a [`DispatchError`],
+/// and dropping the returned future cancels the main-thread future.
+#[track_caller]
+pub fn on_main_async<F, Fut>(f: F) -> impl Future<Output = Fut::Output> + Send
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future + 'static,
+    Fut::Output: Send + 'static,
+{
+    let mut handle = spawn_main(f);
+    handle.abort_on_drop = true;
+
+    async move { (&mut handle).await.unwrap_or_else(|e| e.raise()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn main_join_handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MainJoinHandle<u32>>();
+    }
+
+    // Never registers a main loop: other tests rely on none running.
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    mod without_main_loop {
+        use super::super::*;
+        use std::sync::atomic::AtomicBool;
+        use std::time::Duration;
+
+        #[tokio::test]
+        async fn spawn_main_fails_without_main_loop() {
+            crate::set_main_loop_grace_period(Duration::from_millis(10));
+            let called = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&called);
+
+            let result = spawn_main(move || {
+                flag.store(true, Ordering::SeqCst);
+                async {}
+            })
+            .await;
+            assert!(matches!(result, Err(DispatchError::NotRunning)));
+            assert!(!called.load(Ordering::SeqCst));
+        }
+    }
+
+    // On macOS, or with an emulated main thread, these need a running main
+    // loop; see harness_integration.rs.
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use super::super::*;
+        use std::rc::Rc;
+
+        /// Yields once, waking itself immediately.
+        struct YieldNow(bool);
+
+        impl Future for YieldNow {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                if self.0 {
+                    return Poll::Ready(());
+                }
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        #[tokio::test]
+        async fn spawn_main_runs_non_send_future() {
+            let result = spawn_main(|| async {
+                let value = Rc::new(21);
+                YieldNow(false).await;
+                *value * 2
+            })
+            .await;
+            assert_eq!(result.unwrap(), 42);
+        }
+
+        #[tokio::test]
+        async fn spawn_main_is_woken_from_other_threads() {
+            let (tx, rx) = oneshot::channel();
+            let handle = spawn_main(|| async move { rx.await.unwrap() });
+
+            tokio::spawn(async move { tx.send("hello").unwrap() });
+
+            assert_eq!(handle.await.unwrap(), "hello");
+        }
+
+        #[tokio::test]
+        async fn spawn_main_reports_panic() {
+            let err = spawn_main(|| async { panic!("boom") }).await.unwrap_err();
+            let DispatchError::Panicked(panic) = err else {
+                panic!("expected Panicked, got {err:?}");
+            };
+            assert_eq!(panic.message(), Some("boom"));
+        }
+
+        #[tokio::test]
+        async fn spawn_main_abort_drops_future() {
+            let (_tx, rx) = oneshot::channel::<()>();
+            let handle = spawn_main(|| async move { rx.await.unwrap() });
+            handle.abort();
+            assert!(matches!(handle.await, Err(DispatchError::Cancelled)));
+        }
+
+        #[tokio::test]
+        async fn on_main_async_returns_output() {
+            let result = on_main_async(|| async { Rc::new("hello").to_string() }).await;
+            assert_eq!(result, "hello");
+        }
+    }
+}
@@ -12,6 +12,9 @@
 //! - **Tokio threads**: Run your async code via `#[apple_main::main]` or `#[harness_test]`
 //! - **`on_main()`/`on_main_sync()`**: Dispatch closures to the main thread from tokio
 //!
+//! `on_main()` returns a [`MainTask`], which can be stored, awaited, or aborted.
+//! Dropping it before the main thread reaches the closure cancels the closure.
+//!
 //! ```text
 //! ┌─────────────────┐     dispatch      ┌─────────────────┐
 //! │  Tokio Thread   │ ───────────────▶  │   Main Thread   │
@@ -68,22 +71,73 @@
 //!
 //! # Cross-Platform Support
 //!
-//! All APIs work transparently on non-Apple platforms:
-//! - `on_main()` / `on_main_sync()` execute inline (no thread switching)
+//! All APIs work transparently on non-Apple platforms. By default:
+//! - `on_main()` / `on_main_sync()` execute inline (no thread switching); `on_main()`
+//!   runs its closure when the returned [`MainTask`] is first polled
+//! - `spawn_main()` futures run on a dedicated background thread
 //! - `is_main_thread()` always returns `true`
 //! - `#[apple_main::main]` expands to standard `#[tokio::main]`
 //!
+//! With the `emulated-main-thread` feature, non-Apple platforms mirror the macOS
+//! threading model instead: `#[apple_main::main]` and `test_main!()` park the
+//! real main thread in a portable run loop, `on_main()` hops to it, and
+//! `is_main_thread()` only returns `true` there. This lets Linux CI catch code
+//! that touches main-thread state from a tokio worker.
+//!
 //! This means you can write cross-platform code that "just works" everywhere.
 
+mod actor;
+mod batch;
+mod bound;
+mod capacity;
+mod channel;
+mod coalesce;
+mod completion;
+pub mod context;
 mod dispatch;
+mod error;
+mod executor;
+mod main_loop;
+mod main_thread;
+mod metrics;
+mod observer;
 mod platform;
+mod queue;
 mod runtime;
+mod scoped;
 mod test_harness;
+mod timer;
 
-pub use apple_main_macros::{harness_test, main, test};
-pub use dispatch::{on_main, on_main_sync};
+pub use actor::{spawn_actor, ActorRef, MainActor};
+pub use apple_main_macros::{harness_test, main, main_thread, test};
+pub use batch::MainBatch;
+pub use bound::{on_main_bound, MainBound};
+pub use capacity::{main_queue_limit, set_main_queue_limit};
+pub use channel::{
+    main_channel, main_consumer, MainConsumer, MainReceiver, MainSender, SendError, TrySendError,
+};
+pub use coalesce::on_main_coalesced;
+pub use completion::{call_on_main_with_completion, completion, Completer, CompletionFuture};
+pub use dispatch::{
+    on_main, on_main_named, on_main_sync, on_main_sync_named, on_main_sync_timeout,
+    on_main_sync_with, on_main_sync_with_priority, on_main_timeout, on_main_with,
+    on_main_with_priority, try_on_main, try_on_main_now, try_on_main_sync,
+    try_on_main_sync_with_priority, try_on_main_with_priority, Deadline, MainTask, Priority,
+    TryMainTask, STARVATION_LIMIT,
+};
+pub use error::{DispatchError, MainPanic};
+pub use executor::{on_main_async, spawn_main, MainJoinHandle};
+pub use main_loop::{
+    is_main_loop_running, register_main_loop, set_main_loop_grace_period, DEFAULT_GRACE_PERIOD,
+};
+pub use main_thread::MainThread;
+pub use metrics::{metrics, DispatchMetrics, LatencyHistogram, MetricsSnapshot};
+pub use observer::{observe_main_loop, on_main_idle, MainLoopEvent, MainLoopObserver};
+pub use queue::{on_queue, on_queue_sync, SerialQueue};
 pub use runtime::{block_on, init_runtime, runtime};
+pub use scoped::on_main_scoped;
 pub use test_harness::{run_tests, TestCase};
+pub use timer::{main_after, main_interval, MainTimer};
 
 #[cfg(feature = "unstable-test-framework")]
 pub use test_harness::test_runner;
@@ -156,6 +210,7 @@ pub fn criterion_runner(benchmarks: &[&dyn Fn()]) {
 pub mod __internal {
     #[cfg(target_os = "macos")]
     pub fn run_main_loop() -> ! {
+        crate::main_loop::set_running(true);
         // SAFETY: CFRunLoopRun is safe to call from the main thread.
         // This function is designed to be the main thread's blocking event loop.
         // It has no preconditions beyond being called from a thread with a runloop.
@@ -182,7 +237,17 @@ pub mod __internal {
         fn CFRunLoopRun();
     }
 
-    #[cfg(not(target_os = "macos"))]
+    #[cfg(all(not(target_os = "macos"), feature = "emulated-main-thread"))]
+    pub fn run_main_loop() -> ! {
+        crate::platform::other::run_main_loop()
+    }
+
+    #[cfg(all(not(target_os = "macos"), feature = "emulated-main-thread"))]
+    pub fn record_main_thread() {
+        crate::platform::other::record_main_thread();
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
     pub fn run_main_loop() -> ! {
         panic!("run_main_loop should not be called on non-macOS platforms")
     }
@@ -192,6 +257,21 @@ pub mod __internal {
         ::std::process::exit(code);
     }
 
+    /// Spawns the body of `#[apple_main::main]` and exits once it finishes:
+    /// with 0, or with 101 like a panicking `main` if it panicked, since the
+    /// main loop would otherwise keep the process alive.
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    pub fn spawn_main_body<F>(rt: &::tokio::runtime::Runtime, body: F)
+    where
+        F: ::std::future::Future<Output = ()> + Send + 'static,
+    {
+        let body = rt.spawn(body);
+        rt.spawn(async move {
+            let code = if body.await.is_ok() { 0 } else { 101 };
+            exit_main_loop(code);
+        });
+    }
+
     #[cfg(all(feature = "criterion", target_os = "macos"))]
     pub fn run_criterion_on_thread<F>(run_benchmarks: F)
     where
@@ -219,7 +299,9 @@ pub mod __internal {
             tx.send(()).expect("failed to send start signal to benchmark thread");
         });
 
+        crate::main_loop::set_running(true);
         ::core_foundation::runloop::CFRunLoop::run_current();
+        crate::main_loop::set_running(false);
     }
 
     #[cfg(all(feature = "criterion", not(target_os = "macos")))]
@@ -263,6 +345,37 @@ macro_rules! test_main {
     };
 }
 
+/// Runs the body of `#[apple_main::main]` on non-macOS platforms.
+///
+/// Defined here rather than in the proc macro so that the
+/// `emulated-main-thread` feature is checked against this crate, not the
+/// caller's.
+#[cfg(not(feature = "emulated-main-thread"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __run_main {
+    ($body:block) => {
+        ::tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to create tokio runtime")
+            .block_on(async $body)
+    };
+}
+
+/// Runs the body of `#[apple_main::main]` on a tokio worker while the real
+/// main thread parks in the emulated main loop, as on macOS.
+#[cfg(feature = "emulated-main-thread")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __run_main {
+    ($body:block) => {{
+        $crate::__internal::record_main_thread();
+        $crate::__internal::spawn_main_body($crate::init_runtime(), async $body);
+        $crate::__internal::run_main_loop();
+    }};
+}
+
 /// Macro to generate a main function for Criterion benchmarks.
 ///
 /// This replaces `criterion_main!` and handles CFRunLoop setup on macOS
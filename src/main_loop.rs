@@ -0,0 +1,242 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long dispatch waits for a main loop to start by default.
+///
+/// Long enough to cover the startup race in `#[apple_main::main]`, where the
+/// async body is spawned just before the main loop starts.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+static RUNNING: LoopState = LoopState::new();
+static GRACE_PERIOD_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_GRACE_PERIOD.as_nanos() as u64);
+
+/// Whether a main loop is running, with a way to wait for one to start.
+struct LoopState {
+    running: Mutex<bool>,
+    started: Condvar,
+}
+
+impl LoopState {
+    const fn new() -> Self {
+        Self {
+            running: Mutex::new(false),
+            started: Condvar::new(),
+        }
+    }
+
+    fn set(&self, running: bool) {
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = running;
+        if running {
+            self.started.notify_all();
+        }
+    }
+
+    fn get(&self) -> bool {
+        *self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Blocks for at most `timeout` until a loop is running. Returns whether
+    /// one is.
+    fn wait(&self, timeout: Duration) -> bool {
+        let running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let (running, _) = self
+            .started
+            .wait_timeout_while(running, timeout, |running| !*running)
+            .unwrap_or_else(|e| e.into_inner());
+        *running
+    }
+}
+
+pub(crate) fn set_running(running: bool) {
+    RUNNING.set(running);
+}
+
+/// Whether a main loop is running to pick up work dispatched to the main
+/// thread.
+///
+/// This is set by `#[apple_main::main]`, `test_main!()`, and
+/// [`register_main_loop`]. On non-macOS platforms dispatch runs inline and
+/// never waits for a main loop.
+pub fn is_main_loop_running() -> bool {
+    RUNNING.get()
+}
+
+/// Tells apple_main that the main thread runs a loop it did not start itself,
+/// such as `NSApplication::run` or `CFRunLoopRun` in a host application.
+///
+/// Without a main loop, dispatching to the main thread fails with
+/// [`DispatchError::NotRunning`](crate::DispatchError::NotRunning) after the
+/// [grace period](set_main_loop_grace_period), rather than hanging forever.
+/// Programs using `#[apple_main::main]` or `test_main!()` do not need this.
+pub fn register_main_loop() {
+    set_running(true);
+}
+
+/// Sets how long dispatch waits for a main loop to start before failing with
+/// [`DispatchError::NotRunning`](crate::DispatchError::NotRunning).
+///
+/// Defaults to [`DEFAULT_GRACE_PERIOD`]. A zero grace period fails right away.
+pub fn set_main_loop_grace_period(grace: Duration) {
+    let nanos = u64::try_from(grace.as_nanos()).unwrap_or(u64::MAX);
+    GRACE_PERIOD_NANOS.store(nanos, Ordering::Relaxed);
+}
+
+fn grace_period() -> Duration {
+    Duration::from_nanos(GRACE_PERIOD_NANOS.load(Ordering::Relaxed))
+}
+
+/// Blocks until a main loop is running, for at most the grace period and never
+/// past `limit`. Returns whether one is running.
+pub(crate) fn wait_until_running(limit: Option<Instant>) -> bool {
+    let mut timeout = grace_period();
+    if let Some(limit) = limit {
+        timeout = timeout.min(limit.saturating_duration_since(Instant::now()));
+    }
+    RUNNING.wait(timeout)
+}
+
+/// Calls `on_timeout` from a helper thread if no main loop starts within the
+/// grace period, for queued work that no task polls a [`Watchdog`] for.
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+pub(crate) fn on_not_running<F>(on_timeout: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    if is_main_loop_running() {
+        return;
+    }
+    thread::spawn(move || {
+        if !wait_until_running(None) {
+            on_timeout();
+        }
+    });
+}
+
+/// Fires once the grace period for a queued task has passed, so the task can
+/// fail if no main loop has started in the meantime.
+///
+/// The deadline is kept by a plain thread rather than a tokio timer, so tasks
+/// can be polled by any executor.
+pub(crate) struct Watchdog {
+    expires: Instant,
+    // The waker to wake at `expires`, shared with the timer thread once the
+    // watchdog has been polled.
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Watchdog {
+    /// Starts a watchdog for a task queued now, or returns `None` if a main
+    /// loop is already running.
+    pub(crate) fn start() -> Option<Self> {
+        if is_main_loop_running() {
+            return None;
+        }
+        Some(Self::until(Instant::now() + grace_period()))
+    }
+
+    /// A watchdog that fires at `expires`, for deadlines other than the grace
+    /// period.
+    pub(crate) fn until(expires: Instant) -> Self {
+        Self {
+            expires,
+            waker: None,
+        }
+    }
+
+    /// Resolves once the grace period has passed.
+    pub(crate) fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.expires {
+            return Poll::Ready(());
+        }
+
+        match &self.waker {
+            Some(waker) => waker
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone_from(cx.waker()),
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                // Holds only a weak reference, so a dropped task is not woken.
+                let timer = Arc::downgrade(&waker);
+                let expires = self.expires;
+                thread::spawn(move || {
+                    thread::sleep(expires.saturating_duration_since(Instant::now()));
+                    if let Some(waker) = timer.upgrade() {
+                        waker
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .wake_by_ref();
+                    }
+                });
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Never registers a main loop: other tests rely on none running.
+    #[test]
+    fn wait_until_running_gives_up_at_limit() {
+        let limit = Instant::now() + Duration::from_millis(10);
+        assert!(!wait_until_running(Some(limit)));
+        assert!(Instant::now() >= limit);
+    }
+
+    // Uses its own state, since registering the process-wide main loop would
+    // break tests that rely on none running.
+    #[test]
+    fn wait_until_running_times_out_then_wakes_on_register() {
+        static STATE: LoopState = LoopState::new();
+        assert!(!STATE.wait(Duration::from_millis(10)));
+
+        let waiter = thread::spawn(|| STATE.wait(Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        STATE.set(true);
+        assert!(waiter.join().unwrap());
+        assert!(STATE.get());
+    }
+
+    #[tokio::test]
+    async fn watchdog_expires_after_grace_period() {
+        let mut watchdog = Watchdog {
+            expires: Instant::now() + Duration::from_millis(10),
+            waker: None,
+        };
+        std::future::poll_fn(|cx| watchdog.poll_expired(cx)).await;
+        assert!(Instant::now() >= watchdog.expires);
+    }
+
+    #[test]
+    fn watchdog_expires_without_tokio() {
+        use std::task::Wake;
+
+        struct Unpark(thread::Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let mut watchdog = Watchdog {
+            expires: Instant::now() + Duration::from_millis(10),
+            waker: None,
+        };
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        while watchdog
+            .poll_expired(&mut Context::from_waker(&waker))
+            .is_pending()
+        {
+            thread::park();
+        }
+        assert!(Instant::now() >= watchdog.expires);
+    }
+}
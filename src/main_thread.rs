@@ -0,0 +1,74 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Proof that the current code is running on the main thread.
+///
+/// `MainThread` is zero-sized, `Copy`, and neither `Send` nor `Sync`, so a
+/// value can only exist on the thread it was created on. Wrapper types can
+/// take it as a parameter to have the compiler, rather than code review,
+/// enforce that a method is only called on the main thread:
+///
+/// ```ignore
+/// impl VirtualMachine {
+///     fn start(&self, _mt: MainThread) { /* main-thread-only API */ }
+/// }
+///
+/// apple_main::on_main_with(move |mt| vm.start(mt)).await;
+/// ```
+#[derive(Clone, Copy)]
+pub struct MainThread {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl MainThread {
+    /// Returns a token if called on the main thread, `None` otherwise.
+    pub fn new() -> Option<Self> {
+        crate::is_main_thread().then(|| {
+            // SAFETY: we just checked that this is the main thread.
+            unsafe { Self::new_unchecked() }
+        })
+    }
+
+    /// Returns a token without checking the current thread.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called on the main thread.
+    pub unsafe fn new_unchecked() -> Self {
+        debug_assert!(
+            crate::is_main_thread(),
+            "MainThread created off the main thread"
+        );
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for MainThread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MainThread")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn main_thread_is_zero_sized() {
+        assert_eq!(std::mem::size_of::<MainThread>(), 0);
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    #[test]
+    fn new_succeeds_where_every_thread_is_main() {
+        assert!(MainThread::new().is_some());
+    }
+
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    #[test]
+    fn new_fails_on_test_thread() {
+        assert!(MainThread::new().is_none());
+    }
+}
@@ -0,0 +1,568 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets, from 50µs to 10s.
+const BOUNDS: [Duration; 17] = [
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_micros(2_500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2_500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+static TOTAL: Stats = Stats::new();
+static NAMED: Registry = Mutex::new(BTreeMap::new());
+static QUEUES: Registry = Mutex::new(BTreeMap::new());
+
+type Registry = Mutex<BTreeMap<&'static str, &'static Stats>>;
+
+struct Histogram {
+    // One counter per bound, plus one for everything above the last bound.
+    buckets: [AtomicU64; BOUNDS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BOUNDS.len() + 1],
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let bucket = BOUNDS.partition_point(|bound| *bound < elapsed);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Live counters for one group of dispatched closures.
+struct Stats {
+    dispatched: AtomicU64,
+    queued: AtomicU64,
+    wait: Histogram,
+    run: Histogram,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            dispatched: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            wait: Histogram::new(),
+            run: Histogram::new(),
+        }
+    }
+
+    fn registered(registry: &Registry, key: &'static str) -> &'static Stats {
+        let mut registry = registry.lock().unwrap_or_else(|e| e.into_inner());
+        // Keys are static strings, so there are only ever a handful of them.
+        registry
+            .entry(key)
+            .or_insert_with(|| Box::leak(Box::new(Stats::new())))
+    }
+
+    fn snapshot(&self) -> DispatchMetrics {
+        DispatchMetrics {
+            dispatched: self.dispatched.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            wait: self.wait.snapshot(),
+            run: self.run.snapshot(),
+        }
+    }
+}
+
+/// Where a dispatched closure runs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Target {
+    /// The main thread, optionally under a name from `on_main_named`.
+    Main(Option<&'static str>),
+    /// The [`SerialQueue`](crate::SerialQueue) with this label.
+    Queue(&'static str),
+}
+
+/// Tracks one dispatched closure from submission until it finishes running.
+///
+/// Dropping a queued probe without running it, as happens when the closure is
+/// cancelled, takes it off the queued gauge.
+///
+/// With the `tracing` feature, the probe also carries the caller's span across
+/// the hop to the main thread or serial queue.
+pub(crate) struct Probe {
+    #[cfg(feature = "tracing")]
+    target: Target,
+    stats: &'static Stats,
+    named: Option<&'static Stats>,
+    submitted: Instant,
+    queued: bool,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Probe {
+    /// Counts a closure that was queued on its target.
+    pub(crate) fn queued(target: Target) -> Self {
+        let probe = Self::new(target, true);
+        probe.each(|stats| stats.queued.fetch_add(1, Ordering::Relaxed));
+        probe
+    }
+
+    /// Counts a closure that runs inline, without waiting in the queue.
+    pub(crate) fn inline(target: Target) -> Self {
+        Self::new(target, false)
+    }
+
+    fn new(target: Target, queued: bool) -> Self {
+        let (stats, named) = match target {
+            Target::Main(name) => (&TOTAL, name.map(|name| Stats::registered(&NAMED, name))),
+            Target::Queue(label) => (Stats::registered(&QUEUES, label), None),
+        };
+        let probe = Self {
+            #[cfg(feature = "tracing")]
+            target,
+            stats,
+            named,
+            submitted: Instant::now(),
+            queued,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+        };
+        probe.each(|stats| stats.dispatched.fetch_add(1, Ordering::Relaxed));
+        probe
+    }
+
+    /// Runs `f`, recording how long it waited to start and how long it ran.
+    ///
+    /// With the `tracing` feature, `f` runs inside an `on_main` or `on_queue`
+    /// span, a child of the caller's span that records the wait and run times.
+    pub(crate) fn run<R>(mut self, f: impl FnOnce() -> R) -> R {
+        let started = Instant::now();
+        let wait = if self.queued {
+            self.leave_queue();
+            started - self.submitted
+        } else {
+            Duration::ZERO
+        };
+        self.each(|stats| stats.wait.record(wait));
+
+        #[cfg(feature = "tracing")]
+        let _caller = self.span.enter();
+        #[cfg(feature = "tracing")]
+        let hop = match self.target {
+            Target::Main(name) => tracing::debug_span!(
+                "on_main",
+                name,
+                wait = ?wait,
+                run = tracing::field::Empty,
+            ),
+            Target::Queue(queue) => tracing::debug_span!(
+                "on_queue",
+                queue,
+                wait = ?wait,
+                run = tracing::field::Empty,
+            ),
+        };
+
+        let result = {
+            #[cfg(feature = "tracing")]
+            let _hop = hop.enter();
+            f()
+        };
+
+        let elapsed = started.elapsed();
+        #[cfg(feature = "tracing")]
+        hop.record("run", tracing::field::debug(elapsed));
+        self.each(|stats| stats.run.record(elapsed));
+        result
+    }
+
+    fn leave_queue(&mut self) {
+        self.queued = false;
+        self.each(|stats| stats.queued.fetch_sub(1, Ordering::Relaxed));
+    }
+
+    fn each<T>(&self, mut f: impl FnMut(&Stats) -> T) {
+        f(self.stats);
+        if let Some(named) = self.named {
+            f(named);
+        }
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if self.queued {
+            self.leave_queue();
+        }
+    }
+}
+
+/// Returns a snapshot of the dispatch metrics.
+///
+/// Covers closures dispatched through the [`on_main`](crate::on_main) and
+/// [`on_main_sync`](crate::on_main_sync) families and
+/// [`on_main_coalesced`](crate::on_main_coalesced), including those that ran
+/// inline because they were dispatched from the main thread, and closures
+/// dispatched to [serial queues](crate::SerialQueue). The counters are
+/// process-wide and only ever grow, apart from the queued gauge.
+pub fn metrics() -> MetricsSnapshot {
+    MetricsSnapshot {
+        total: TOTAL.snapshot(),
+        by_name: snapshot(&NAMED),
+        by_queue: snapshot(&QUEUES),
+    }
+}
+
+fn snapshot(registry: &Registry) -> BTreeMap<&'static str, DispatchMetrics> {
+    let registry = registry.lock().unwrap_or_else(|e| e.into_inner());
+    registry
+        .iter()
+        .map(|(key, stats)| (*key, stats.snapshot()))
+        .collect()
+}
+
+/// A point-in-time copy of the dispatch metrics, returned by [`metrics`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// All closures dispatched to the main thread, named or not.
+    pub total: DispatchMetrics,
+    /// Closures dispatched with [`on_main_named`](crate::on_main_named) or
+    /// [`on_main_sync_named`](crate::on_main_sync_named), by name.
+    pub by_name: BTreeMap<&'static str, DispatchMetrics>,
+    /// Closures dispatched to [serial queues](crate::SerialQueue), by queue
+    /// label.
+    pub by_queue: BTreeMap<&'static str, DispatchMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// Renders the snapshot in the Prometheus text exposition format.
+    ///
+    /// Main-thread totals are exported under `apple_main_*`, the per-name
+    /// breakdown under `apple_main_named_*` with a `name` label, and serial
+    /// queues under `apple_main_queue_*` with a `queue` label, so summing any
+    /// one family never double counts.
+    pub fn to_prometheus(&self) -> String {
+        let total = [(None, &self.total)];
+        let named = labelled("name", &self.by_name);
+        let queues = labelled("queue", &self.by_queue);
+
+        let mut out = String::new();
+        for (prefix, target, series) in [
+            ("apple_main", "the main thread", &total[..]),
+            ("apple_main_named", "the main thread", &named[..]),
+            ("apple_main_queue", "a serial queue", &queues[..]),
+        ] {
+            if series.is_empty() {
+                continue;
+            }
+            write_counter(
+                &mut out,
+                &format!("{prefix}_dispatched_total"),
+                &format!("Closures dispatched to {target}."),
+                "counter",
+                series,
+                |metrics| metrics.dispatched,
+            );
+            write_counter(
+                &mut out,
+                &format!("{prefix}_queued"),
+                &format!("Closures waiting to run on {target}."),
+                "gauge",
+                series,
+                |metrics| metrics.queued,
+            );
+            write_histogram(
+                &mut out,
+                &format!("{prefix}_wait_seconds"),
+                &format!("Time from dispatch until a closure started on {target}."),
+                series,
+                |metrics| &metrics.wait,
+            );
+            write_histogram(
+                &mut out,
+                &format!("{prefix}_run_seconds"),
+                &format!("Time closures spent running on {target}."),
+                series,
+                |metrics| &metrics.run,
+            );
+        }
+        out
+    }
+}
+
+/// Each series' label, as a key and value, and its metrics.
+type Series<'a> = [(Option<(&'static str, &'static str)>, &'a DispatchMetrics)];
+
+fn labelled<'a>(
+    key: &'static str,
+    metrics: &'a BTreeMap<&'static str, DispatchMetrics>,
+) -> Vec<(Option<(&'static str, &'static str)>, &'a DispatchMetrics)> {
+    metrics
+        .iter()
+        .map(|(value, metrics)| (Some((key, *value)), metrics))
+        .collect()
+}
+
+fn write_counter(
+    out: &mut String,
+    metric: &str,
+    help: &str,
+    kind: &str,
+    series: &Series<'_>,
+    value: impl Fn(&DispatchMetrics) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {metric} {help}");
+    let _ = writeln!(out, "# TYPE {metric} {kind}");
+    for (label, metrics) in series {
+        let _ = writeln!(out, "{metric}{} {}", labels(*label, None), value(metrics));
+    }
+}
+
+fn write_histogram(
+    out: &mut String,
+    metric: &str,
+    help: &str,
+    series: &Series<'_>,
+    histogram: impl Fn(&DispatchMetrics) -> &LatencyHistogram,
+) {
+    let _ = writeln!(out, "# HELP {metric} {help}");
+    let _ = writeln!(out, "# TYPE {metric} histogram");
+    for (label, metrics) in series {
+        let histogram = histogram(metrics);
+        for (bound, count) in histogram.buckets() {
+            let le = bound.map_or_else(|| "+Inf".to_owned(), |b| b.as_secs_f64().to_string());
+            let _ = writeln!(out, "{metric}_bucket{} {count}", labels(*label, Some(&le)));
+        }
+        let labels = labels(*label, None);
+        let _ = writeln!(
+            out,
+            "{metric}_sum{labels} {}",
+            histogram.sum().as_secs_f64()
+        );
+        let _ = writeln!(out, "{metric}_count{labels} {}", histogram.count());
+    }
+}
+
+fn labels(label: Option<(&str, &str)>, le: Option<&str>) -> String {
+    let mut pairs = Vec::new();
+    if let Some((key, value)) = label {
+        let escaped = value
+            .replace('\\', r"\\")
+            .replace('"', r#"\""#)
+            .replace('\n', r"\n");
+        pairs.push(format!("{key}=\"{escaped}\""));
+    }
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Counters for a group of closures dispatched to the main thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DispatchMetrics {
+    /// How many closures were dispatched, including cancelled ones.
+    pub dispatched: u64,
+    /// How many closures are currently waiting in the main queue.
+    pub queued: u64,
+    /// Time from dispatch until each closure started running. Closures that
+    /// ran inline record zero.
+    pub wait: LatencyHistogram,
+    /// Time each closure spent running on the main thread.
+    pub run: LatencyHistogram,
+}
+
+/// A histogram of durations with fixed buckets from 50µs to 10s.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    /// How many durations were recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The sum of all recorded durations.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The mean recorded duration, or `None` if nothing was recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count())
+            .ok()
+            .filter(|count| *count > 0)?;
+        Some(self.sum / count)
+    }
+
+    /// Cumulative bucket counts: each upper bound paired with how many
+    /// durations were at or below it. The last bucket has no bound and
+    /// counts everything.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        let bounds = BOUNDS.iter().copied().map(Some).chain([None]);
+        bounds
+            .zip(self.buckets.iter().chain(std::iter::repeat(&0)))
+            .scan(0, |total, (bound, count)| {
+                *total += count;
+                Some((bound, *total))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::from_micros(10));
+        histogram.record(Duration::from_millis(1));
+        histogram.record(Duration::from_secs(60));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 3);
+        let buckets: Vec<_> = snapshot.buckets().collect();
+        assert_eq!(buckets[0], (Some(Duration::from_micros(50)), 1));
+        assert_eq!(buckets[4], (Some(Duration::from_millis(1)), 2));
+        assert_eq!(buckets[BOUNDS.len() - 1].1, 2);
+        assert_eq!(buckets[BOUNDS.len()], (None, 3));
+    }
+
+    #[test]
+    fn histogram_mean() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.snapshot().mean(), None);
+
+        histogram.record(Duration::from_millis(1));
+        histogram.record(Duration::from_millis(3));
+        assert_eq!(histogram.snapshot().mean(), Some(Duration::from_millis(2)));
+    }
+
+    #[test]
+    fn probe_records_wait_and_run_time() {
+        let probe = Probe::queued(Target::Main(Some("metrics.probe")));
+        assert_eq!(metrics().by_name["metrics.probe"].queued, 1);
+
+        probe.run(|| std::thread::sleep(Duration::from_millis(2)));
+
+        let named = &metrics().by_name["metrics.probe"];
+        assert_eq!(named.dispatched, 1);
+        assert_eq!(named.queued, 0);
+        assert_eq!(named.wait.count(), 1);
+        assert!(named.run.sum() >= Duration::from_millis(2));
+    }
+
+    #[test]
+    fn dropped_probe_leaves_queue_without_recording() {
+        drop(Probe::queued(Target::Main(Some("metrics.dropped"))));
+
+        let named = &metrics().by_name["metrics.dropped"];
+        assert_eq!(named.dispatched, 1);
+        assert_eq!(named.queued, 0);
+        assert_eq!(named.wait.count(), 0);
+        assert_eq!(named.run.count(), 0);
+    }
+
+    #[test]
+    fn inline_probe_records_zero_wait() {
+        Probe::inline(Target::Main(Some("metrics.inline"))).run(|| ());
+
+        let named = &metrics().by_name["metrics.inline"];
+        assert_eq!(named.queued, 0);
+        assert_eq!(named.wait.sum(), Duration::ZERO);
+        assert_eq!(named.run.count(), 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn probe_runs_closure_in_hop_span_under_caller_span() {
+        use tracing_subscriber::registry::{LookupSpan, Registry};
+
+        fn spans(target: Target) -> (&'static str, &'static str) {
+            let probe = tracing::info_span!("caller").in_scope(|| Probe::queued(target));
+            probe.run(|| {
+                let id = tracing::Span::current().id().unwrap();
+                tracing::dispatcher::get_default(|dispatch| {
+                    let registry = dispatch.downcast_ref::<Registry>().unwrap();
+                    let span = registry.span(&id).unwrap();
+                    (span.name(), span.parent().unwrap().name())
+                })
+            })
+        }
+
+        tracing::subscriber::with_default(Registry::default(), || {
+            assert_eq!(spans(Target::Main(None)), ("on_main", "caller"));
+            assert_eq!(spans(Target::Queue("metrics.span")), ("on_queue", "caller"));
+        });
+    }
+
+    #[test]
+    fn prometheus_export() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::from_millis(1));
+        let metrics = DispatchMetrics {
+            dispatched: 2,
+            queued: 1,
+            wait: histogram.snapshot(),
+            run: histogram.snapshot(),
+        };
+        let snapshot = MetricsSnapshot {
+            total: metrics.clone(),
+            by_name: BTreeMap::from([("vm \"start\"", metrics.clone())]),
+            by_queue: BTreeMap::from([("vm-io", metrics)]),
+        };
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("# TYPE apple_main_dispatched_total counter\n"));
+        assert!(text.contains("\napple_main_dispatched_total 2\n"));
+        assert!(text.contains("\napple_main_queued 1\n"));
+        assert!(text.contains("\napple_main_wait_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(text.contains("\napple_main_run_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("\napple_main_run_seconds_sum 0.001\n"));
+        assert!(text.contains("\napple_main_run_seconds_count 1\n"));
+        assert!(text.contains("\napple_main_named_dispatched_total{name=\"vm \\\"start\\\"\"} 2\n"));
+        assert!(text.contains(
+            "\napple_main_named_wait_seconds_bucket{name=\"vm \\\"start\\\"\",le=\"0.00005\"} 0\n"
+        ));
+        assert!(text.contains(
+            "# HELP apple_main_queue_queued Closures waiting to run on a serial queue.\n"
+        ));
+        assert!(text.contains("\napple_main_queue_run_seconds_count{queue=\"vm-io\"} 1\n"));
+    }
+}
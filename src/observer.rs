@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::dispatch::{catch, has_queued_jobs, task, Job, MainTask};
+use crate::executor::schedule;
+use crate::metrics::Target;
+
+type Callback = Box<dyn FnMut(MainLoopEvent) + Send>;
+
+static OBSERVERS: Mutex<Vec<Arc<Observer>>> = Mutex::new(Vec::new());
+static IDLE: Mutex<VecDeque<Job>> = Mutex::new(VecDeque::new());
+
+/// A point in the main loop's cycle, reported to
+/// [`observe_main_loop`] callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MainLoopEvent {
+    /// The loop has run out of work and is about to sleep.
+    BeforeWaiting,
+    /// The loop woke up, because work arrived or a timer fired.
+    AfterWaiting,
+    /// The loop is about to sleep and no dispatched closures are queued.
+    /// Follows [`BeforeWaiting`](Self::BeforeWaiting), and is when
+    /// [`on_main_idle`] closures run.
+    Idle,
+}
+
+struct Observer {
+    // Taken while the callback runs, and dropped once the observer is removed.
+    callback: Mutex<Option<Callback>>,
+    removed: AtomicBool,
+    location: &'static Location<'static>,
+}
+
+impl Observer {
+    fn remove(&self) {
+        self.removed.store(true, Ordering::Release);
+        self.callback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+    }
+
+    fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Acquire)
+    }
+
+    /// Reports `event` to the callback. A panic removes the observer.
+    fn notify(&self, event: MainLoopEvent) {
+        if self.is_removed() {
+            return;
+        }
+        let Some(mut callback) = self
+            .callback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        else {
+            return;
+        };
+
+        if catch(|| callback(event), self.location).is_err() {
+            self.removed.store(true, Ordering::Release);
+            return;
+        }
+        if !self.is_removed() {
+            *self.callback.lock().unwrap_or_else(|e| e.into_inner()) = Some(callback);
+        }
+    }
+}
+
+/// A handle to a callback registered by [`observe_main_loop`].
+///
+/// Dropping the handle removes the callback; call [`detach`](Self::detach) to
+/// keep it registered for the life of the process.
+#[must_use = "dropping a MainLoopObserver removes it"]
+pub struct MainLoopObserver {
+    observer: Option<Arc<Observer>>,
+}
+
+impl MainLoopObserver {
+    /// Keeps the callback registered after the handle is dropped.
+    pub fn detach(mut self) {
+        self.observer = None;
+    }
+}
+
+impl Drop for MainLoopObserver {
+    fn drop(&mut self) {
+        if let Some(observer) = self.observer.take() {
+            observer.remove();
+            OBSERVERS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|registered| !Arc::ptr_eq(registered, &observer));
+        }
+    }
+}
+
+/// Calls `f` on the main thread at each [`MainLoopEvent`] of the main loop's
+/// cycle, until the returned [`MainLoopObserver`] is dropped.
+///
+/// On macOS this is a `CFRunLoopObserver` on the main run loop, in its common
+/// modes. With an emulated main thread the portable run loop reports the same
+/// events; otherwise they come from the background thread that runs
+/// [`spawn_main`](crate::spawn_main) futures. A panic in `f` removes it.
+///
+/// ```ignore
+/// let pacer = apple_main::observe_main_loop(move |event| {
+///     if event == MainLoopEvent::BeforeWaiting {
+///         view.flush_pending_state();
+///     }
+/// });
+/// ```
+#[track_caller]
+pub fn observe_main_loop<F>(f: F) -> MainLoopObserver
+where
+    F: FnMut(MainLoopEvent) + Send + 'static,
+{
+    let observer = Arc::new(Observer {
+        callback: Mutex::new(Some(Box::new(f))),
+        removed: AtomicBool::new(false),
+        location: Location::caller(),
+    });
+    OBSERVERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Arc::clone(&observer));
+    wake();
+
+    MainLoopObserver {
+        observer: Some(observer),
+    }
+}
+
+/// Runs `f` on the main thread the next time the main loop is
+/// [idle](MainLoopEvent::Idle), after every closure queued so far.
+///
+/// Use it for deferred, low-priority work that should not delay anything
+/// already waiting for the main thread. Even when called on the main thread,
+/// `f` is deferred rather than run inline. The returned [`MainTask`] behaves
+/// like one from [`on_main`](crate::on_main), including failing if no main
+/// loop starts within the [grace period](crate::set_main_loop_grace_period).
+///
+/// On non-macOS platforms without the `emulated-main-thread` feature, `on_main`
+/// closures run inline in the awaiting task rather than on the background
+/// thread that runs idle closures, so there is no ordering guarantee between
+/// them.
+#[track_caller]
+pub fn on_main_idle<F, R>(f: F) -> MainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    // Otherwise idle closures run on the background thread, which is always
+    // running.
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    let watchdog = crate::main_loop::Watchdog::start();
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    let watchdog = None;
+    let (job, task) = task(f, Location::caller(), Target::Main(None), watchdog);
+    IDLE.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push_back(job);
+    wake();
+    MainTask(task)
+}
+
+/// Makes sure the main loop reports events, and runs one more cycle so the
+/// new observer or idle closure is seen even if the loop is asleep.
+fn wake() {
+    #[cfg(target_os = "macos")]
+    install();
+    schedule(Box::new(|| {}));
+}
+
+/// Called by the main loop when it has run out of work and is about to sleep.
+pub(crate) fn before_waiting() {
+    notify(MainLoopEvent::BeforeWaiting);
+    if has_queued_jobs() {
+        return;
+    }
+    notify(MainLoopEvent::Idle);
+
+    // Closures queued by these run at the next idle point.
+    let idle = mem::take(&mut *IDLE.lock().unwrap_or_else(|e| e.into_inner()));
+    for job in idle {
+        job();
+    }
+}
+
+/// Called by the main loop when it wakes up.
+pub(crate) fn after_waiting() {
+    notify(MainLoopEvent::AfterWaiting);
+}
+
+fn notify(event: MainLoopEvent) {
+    let observers = OBSERVERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    if observers.is_empty() {
+        return;
+    }
+    for observer in &observers {
+        observer.notify(event);
+    }
+    if observers.iter().any(|observer| observer.is_removed()) {
+        OBSERVERS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|observer| !observer.is_removed());
+    }
+}
+
+/// Adds the `CFRunLoopObserver` that reports to [`before_waiting`] and
+/// [`after_waiting`], once.
+#[cfg(target_os = "macos")]
+fn install() {
+    use std::ffi::c_void;
+    use std::sync::Once;
+
+    use core_foundation::base::{kCFAllocatorDefault, TCFType};
+    use core_foundation::runloop::{
+        kCFRunLoopAfterWaiting, kCFRunLoopBeforeWaiting, kCFRunLoopCommonModes, CFRunLoop,
+        CFRunLoopActivity, CFRunLoopObserver, CFRunLoopObserverCreate, CFRunLoopObserverRef,
+    };
+
+    extern "C" fn callout(_: CFRunLoopObserverRef, activity: CFRunLoopActivity, _: *mut c_void) {
+        if activity == kCFRunLoopBeforeWaiting {
+            before_waiting();
+        } else {
+            after_waiting();
+        }
+    }
+
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        // SAFETY: the callout matches CFRunLoopObserverCallBack and needs no
+        // context, so passing a null one is allowed. The run loop retains the
+        // observer, so it outlives the wrapper dropped below.
+        let observer = unsafe {
+            CFRunLoopObserver::wrap_under_create_rule(CFRunLoopObserverCreate(
+                kCFAllocatorDefault,
+                kCFRunLoopBeforeWaiting | kCFRunLoopAfterWaiting,
+                1,
+                0,
+                callout,
+                std::ptr::null_mut(),
+            ))
+        };
+        // SAFETY: kCFRunLoopCommonModes is an immutable CFString constant.
+        CFRunLoop::get_main().add_observer(&observer, unsafe { kCFRunLoopCommonModes });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observer(callback: Callback) -> Observer {
+        Observer {
+            callback: Mutex::new(Some(callback)),
+            removed: AtomicBool::new(false),
+            location: Location::caller(),
+        }
+    }
+
+    #[test]
+    fn observer_sees_events_until_removed() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = Arc::clone(&seen);
+        let observer = observer(Box::new(move |event| record.lock().unwrap().push(event)));
+
+        observer.notify(MainLoopEvent::BeforeWaiting);
+        observer.notify(MainLoopEvent::Idle);
+        observer.remove();
+        observer.notify(MainLoopEvent::AfterWaiting);
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [MainLoopEvent::BeforeWaiting, MainLoopEvent::Idle]
+        );
+        // Removing drops the callback, and with it `record`.
+        assert_eq!(Arc::strong_count(&seen), 1);
+    }
+
+    #[test]
+    fn panicking_observer_is_removed() {
+        let observer = observer(Box::new(|_| panic!("observer failed")));
+        observer.notify(MainLoopEvent::Idle);
+        assert!(observer.is_removed());
+    }
+
+    #[test]
+    fn dropping_handle_unregisters_observer() {
+        let handle = observe_main_loop(|_| {});
+        let registered = Arc::clone(handle.observer.as_ref().unwrap());
+        drop(handle);
+
+        assert!(registered.is_removed());
+        let observers = OBSERVERS.lock().unwrap();
+        assert!(!observers.iter().any(|o| Arc::ptr_eq(o, &registered)));
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use crate::{observe_main_loop, on_main_idle, MainLoopEvent};
+
+        #[tokio::test]
+        async fn on_main_idle_runs_when_background_loop_goes_idle() {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let _observer = observe_main_loop(move |event| {
+                let _ = tx.send(event);
+            });
+
+            assert_eq!(on_main_idle(|| 42).await, 42);
+            while rx.recv().await != Some(MainLoopEvent::Idle) {}
+        }
+    }
+}
@@ -1,20 +1,360 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+
+use crate::dispatch::Job;
+
+#[cfg(not(feature = "emulated-main-thread"))]
 pub fn is_main_thread() -> bool {
     true
 }
 
+/// Whether the current thread is the process's real main thread, as recorded
+/// when `#[apple_main::main]` or `test_main!()` starts. No thread is the main
+/// thread before then.
+#[cfg(feature = "emulated-main-thread")]
+pub fn is_main_thread() -> bool {
+    MAIN_THREAD.get() == Some(&thread::current().id())
+}
+
+#[cfg(feature = "emulated-main-thread")]
+static MAIN_THREAD: OnceLock<ThreadId> = OnceLock::new();
+
+/// Records the calling thread, which must be the process's main thread, as
+/// the one [`is_main_thread`] reports.
+#[cfg(feature = "emulated-main-thread")]
+pub(crate) fn record_main_thread() {
+    let current = thread::current();
+    // Thread names are only a sanity check: a libtest worker can be named
+    // "main" too, which is why the id is what gets recorded.
+    assert_eq!(
+        current.name(),
+        Some("main"),
+        "the emulated main loop must run on the process's main thread"
+    );
+    let main = *MAIN_THREAD.get_or_init(|| current.id());
+    assert_eq!(main, current.id(), "the main thread was already recorded");
+}
+
+/// A minimal portable run loop: a FIFO of jobs drained by a single thread,
+/// plus timers that join the FIFO once their deadline passes.
+pub(crate) struct RunLoop {
+    state: Mutex<State>,
+    ready: Condvar,
+    // Whether this is a home loop that reports main-loop events.
+    observed: bool,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    timers: BinaryHeap<Timer>,
+    // Breaks deadline ties so timers due at the same instant run in order.
+    next_timer: u64,
+    stopped: bool,
+}
+
+struct Timer {
+    deadline: Instant,
+    seq: u64,
+    job: Job,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    // Reversed, so the max-heap pops the earliest deadline first.
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+impl RunLoop {
+    pub(crate) const fn new() -> Self {
+        Self::with_observers(false)
+    }
+
+    /// A run loop that reports [`MainLoopEvent`](crate::MainLoopEvent)s to
+    /// [`observe_main_loop`](crate::observe_main_loop) callbacks.
+    pub(crate) const fn observed() -> Self {
+        Self::with_observers(true)
+    }
+
+    const fn with_observers(observed: bool) -> Self {
+        Self {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                timers: BinaryHeap::new(),
+                next_timer: 0,
+                stopped: false,
+            }),
+            ready: Condvar::new(),
+            observed,
+        }
+    }
+
+    pub(crate) fn enqueue(&self, job: Job) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .queue
+            .push_back(job);
+        self.ready.notify_one();
+    }
+
+    /// Queues `job` once `deadline` has passed.
+    pub(crate) fn enqueue_at(&self, deadline: Instant, job: Job) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = state.next_timer;
+        state.next_timer += 1;
+        state.timers.push(Timer { deadline, seq, job });
+        drop(state);
+        // The new timer may be due before the one the loop is waiting on.
+        self.ready.notify_one();
+    }
+
+    /// Runs jobs on the current thread as they arrive. Never returns.
+    pub(crate) fn run(&self) -> ! {
+        loop {
+            self.run_until_stopped();
+        }
+    }
+
+    /// Runs jobs on the current thread as they arrive, returning once
+    /// [`stop`](Self::stop) was called and the queued jobs have run.
+    pub(crate) fn run_until_stopped(&self) {
+        while let Some(job) = self.next_job() {
+            job();
+        }
+    }
+
+    /// Makes [`run_until_stopped`](Self::run_until_stopped) return once the
+    /// queue is drained. Pending timers are dropped.
+    pub(crate) fn stop(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+        self.ready.notify_all();
+    }
+
+    fn next_job(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // Whether observers were told the loop is about to wait.
+        let mut announced = false;
+        loop {
+            let now = Instant::now();
+            while state
+                .timers
+                .peek()
+                .is_some_and(|timer| timer.deadline <= now)
+            {
+                let timer = state.timers.pop().expect("peeked timer");
+                state.queue.push_back(timer.job);
+            }
+
+            if let Some(job) = state.queue.pop_front() {
+                return Some(job);
+            }
+            if state.stopped {
+                state.timers.clear();
+                return None;
+            }
+            if self.observed && !announced {
+                // Observers and idle closures may queue more work.
+                drop(state);
+                crate::observer::before_waiting();
+                announced = true;
+                state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            state = match state.timers.peek() {
+                Some(timer) => {
+                    let timeout = timer.deadline - now;
+                    self.ready
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.ready.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+            if self.observed {
+                drop(state);
+                crate::observer::after_waiting();
+                announced = false;
+                state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            }
+        }
+    }
+}
+
+/// The run loop the real main thread parks in when the main thread is
+/// emulated, standing in for the macOS main queue.
+#[cfg(feature = "emulated-main-thread")]
+pub(crate) fn main_loop() -> &'static RunLoop {
+    static MAIN_LOOP: RunLoop = RunLoop::observed();
+    &MAIN_LOOP
+}
+
+/// Parks the calling thread, which must be the process's main thread, in
+/// [`main_loop`]. Never returns.
+#[cfg(feature = "emulated-main-thread")]
+pub(crate) fn run_main_loop() -> ! {
+    record_main_thread();
+    crate::main_loop::set_running(true);
+    main_loop().run()
+}
+
+/// A run loop on a lazily spawned background thread.
+///
+/// `on_main` runs closures inline on this platform, but work that must stay on
+/// one thread, like the futures driven by `spawn_main`, needs a loop to run on.
+/// With an emulated main thread that work runs on [`main_loop`] instead.
+#[cfg(not(feature = "emulated-main-thread"))]
+pub(crate) fn background_loop() -> &'static RunLoop {
+    background().0
+}
+
+/// Whether the current thread is the one running [`background_loop`].
+#[cfg(not(feature = "emulated-main-thread"))]
+pub(crate) fn is_background_thread() -> bool {
+    background().1 == thread::current().id()
+}
+
+#[cfg(not(feature = "emulated-main-thread"))]
+fn background() -> &'static (&'static RunLoop, ThreadId) {
+    static BACKGROUND: OnceLock<(&'static RunLoop, ThreadId)> = OnceLock::new();
+
+    BACKGROUND.get_or_init(|| {
+        let run_loop: &'static RunLoop = Box::leak(Box::new(RunLoop::observed()));
+        let handle = thread::Builder::new()
+            .name("apple-main".into())
+            .spawn(move || run_loop.run())
+            .expect("failed to spawn apple-main background thread");
+        (run_loop, handle.thread().id())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn run_loop_runs_jobs_in_order_on_its_thread() {
+        let run_loop: &'static RunLoop = Box::leak(Box::new(RunLoop::new()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            run_loop.enqueue(Box::new(move || tx.send(i).unwrap()));
+        }
+        thread::spawn(move || run_loop.run());
+
+        let received: Vec<_> = rx.iter().take(3).collect();
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn stopped_run_loop_drains_queue_then_returns() {
+        let run_loop = std::sync::Arc::new(RunLoop::new());
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..2 {
+            let tx = tx.clone();
+            run_loop.enqueue(Box::new(move || tx.send(i).unwrap()));
+        }
+        run_loop.enqueue_at(
+            Instant::now() + std::time::Duration::from_secs(60),
+            Box::new(move || tx.send(2).unwrap()),
+        );
+        run_loop.stop();
+
+        let worker = std::sync::Arc::clone(&run_loop);
+        thread::spawn(move || worker.run_until_stopped())
+            .join()
+            .unwrap();
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn run_loop_runs_timers_by_deadline() {
+        use std::time::Duration;
+
+        let run_loop: &'static RunLoop = Box::leak(Box::new(RunLoop::new()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let now = Instant::now();
+        for (i, delay) in [(0, 30), (1, 10), (2, 10)] {
+            let tx = tx.clone();
+            let deadline = now + Duration::from_millis(delay);
+            run_loop.enqueue_at(deadline, Box::new(move || tx.send(i).unwrap()));
+        }
+        let tx_now = tx.clone();
+        run_loop.enqueue(Box::new(move || tx_now.send(3).unwrap()));
+        thread::spawn(move || run_loop.run());
+
+        let received: Vec<_> = rx.iter().take(4).collect();
+        assert_eq!(received, vec![3, 1, 2, 0]);
+        assert!(now.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[cfg(feature = "emulated-main-thread")]
+    #[test]
+    fn is_main_thread_returns_false_on_spawned_thread() {
+        let handle = std::thread::spawn(is_main_thread);
+        assert!(!handle.join().unwrap());
+    }
+
+    // `--test-threads=1` names the libtest worker "main".
+    #[cfg(feature = "emulated-main-thread")]
+    #[test]
+    fn is_main_thread_ignores_thread_name() {
+        let handle = thread::Builder::new()
+            .name("main".into())
+            .spawn(is_main_thread)
+            .unwrap();
+        assert!(!handle.join().unwrap());
+    }
+
+    #[cfg(not(feature = "emulated-main-thread"))]
     #[test]
     fn is_main_thread_always_returns_true() {
         assert!(is_main_thread());
     }
 
+    #[cfg(not(feature = "emulated-main-thread"))]
     #[test]
     fn is_main_thread_returns_true_on_spawned_thread() {
         let handle = std::thread::spawn(is_main_thread);
         let result = handle.join().unwrap();
         assert!(result);
     }
+
+    #[cfg(not(feature = "emulated-main-thread"))]
+    #[test]
+    fn background_loop_runs_jobs_in_order() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            background_loop().enqueue(Box::new(move || tx.send(i).unwrap()));
+        }
+        let received: Vec<_> = rx.iter().take(3).collect();
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[cfg(not(feature = "emulated-main-thread"))]
+    #[test]
+    fn background_loop_runs_on_background_thread() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        background_loop().enqueue(Box::new(move || tx.send(is_background_thread()).unwrap()));
+        assert!(rx.recv().unwrap());
+        assert!(!is_background_thread());
+    }
 }
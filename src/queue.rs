@@ -0,0 +1,221 @@
+use std::cell::Cell;
+use std::panic::Location;
+use std::sync::{mpsc, Arc};
+
+use crate::dispatch::{catch, prepare, task, Job, MainTask};
+use crate::error::DispatchError;
+use crate::metrics::{Probe, Target};
+
+#[cfg(not(target_os = "macos"))]
+use crate::platform::other::RunLoop;
+
+thread_local! {
+    // The queue whose closure is running on this thread, if any.
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A labelled serial queue for work that must not run concurrently, but is
+/// not bound to the main thread.
+///
+/// Closures dispatched with [`on_queue`] and [`on_queue_sync`] run one at a
+/// time, in the order they were dispatched. On macOS the queue is a GCD serial
+/// queue; elsewhere it is a dedicated thread named after the label.
+///
+/// Handles are cheap to clone. Once the last one is dropped, closures already
+/// queued still run, and then the queue shuts down.
+///
+/// ```ignore
+/// let io = apple_main::SerialQueue::new("vm-io");
+/// let device = VirtioDevice::new(); // not thread-safe
+/// apple_main::on_queue(&io, move || device.flush()).await;
+/// ```
+#[derive(Clone)]
+pub struct SerialQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    label: &'static str,
+    #[cfg(target_os = "macos")]
+    queue: dispatch::Queue,
+    #[cfg(not(target_os = "macos"))]
+    run_loop: Arc<RunLoop>,
+}
+
+#[cfg(not(target_os = "macos"))]
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.run_loop.stop();
+    }
+}
+
+impl SerialQueue {
+    /// Creates a serial queue. The label names the queue in debuggers,
+    /// [metrics](crate::metrics), and tracing spans.
+    #[cfg(target_os = "macos")]
+    pub fn new(label: &'static str) -> Self {
+        let queue = dispatch::Queue::create(label, dispatch::QueueAttribute::Serial);
+        Self {
+            inner: Arc::new(Inner { label, queue }),
+        }
+    }
+
+    /// Creates a serial queue. The label names the queue in debuggers,
+    /// [metrics](crate::metrics), and tracing spans.
+    #[cfg(not(target_os = "macos"))]
+    pub fn new(label: &'static str) -> Self {
+        let run_loop = Arc::new(RunLoop::new());
+        let worker = Arc::clone(&run_loop);
+        std::thread::Builder::new()
+            .name(label.into())
+            .spawn(move || worker.run_until_stopped())
+            .expect("failed to spawn serial queue thread");
+        Self {
+            inner: Arc::new(Inner { label, run_loop }),
+        }
+    }
+
+    /// The label this queue was created with.
+    pub fn label(&self) -> &'static str {
+        self.inner.label
+    }
+
+    /// Whether the caller is a closure running on this queue.
+    pub fn is_current(&self) -> bool {
+        CURRENT.with(Cell::get) == self.id()
+    }
+
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+
+    fn exec(&self, job: Job) {
+        let id = self.id();
+        let job: Job = Box::new(move || {
+            let previous = CURRENT.with(|current| current.replace(id));
+            job();
+            CURRENT.with(|current| current.set(previous));
+        });
+
+        #[cfg(target_os = "macos")]
+        self.inner.queue.exec_async(job);
+        #[cfg(not(target_os = "macos"))]
+        self.inner.run_loop.enqueue(job);
+    }
+}
+
+impl std::fmt::Debug for SerialQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SerialQueue")
+            .field("label", &self.label())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Dispatches `f` to `queue` and returns a [`MainTask`] for its result.
+///
+/// The task behaves like one returned by [`on_main`](crate::on_main): a panic
+/// in `f` is resumed in whichever task awaits it, and dropping it before the
+/// queue reaches `f` cancels the closure.
+#[track_caller]
+pub fn on_queue<F, R>(queue: &SerialQueue, f: F) -> MainTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (job, task) = task(f, Location::caller(), Target::Queue(queue.label()), None);
+    queue.exec(job);
+    MainTask(task)
+}
+
+/// Runs `f` on `queue` and blocks until it returns.
+///
+/// A panic in `f` is resumed in the caller. When called from a closure
+/// already running on `queue`, `f` runs inline instead of deadlocking.
+#[track_caller]
+pub fn on_queue_sync<F, R>(queue: &SerialQueue, f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let location = Location::caller();
+    let target = Target::Queue(queue.label());
+
+    let result = if queue.is_current() {
+        Probe::inline(target).run(|| catch(f, location))
+    } else {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let run = prepare(f, location, target);
+        queue.exec(Box::new(move || {
+            let _ = tx.send(run());
+        }));
+        rx.recv().unwrap_or(Err(DispatchError::ShuttingDown))
+    };
+    result.unwrap_or_else(|e| e.raise())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn on_queue_sync_returns_value_on_queue() {
+        let queue = SerialQueue::new("queue.sync");
+        let (value, on_queue) = on_queue_sync(&queue, || (42, CURRENT.with(Cell::get) != 0));
+        assert_eq!(value, 42);
+        assert!(on_queue);
+        assert!(!queue.is_current());
+    }
+
+    #[tokio::test]
+    async fn on_queue_runs_closures_in_order() {
+        let queue = SerialQueue::new("queue.order");
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let tasks: Vec<_> = (0..5)
+            .map(|i| {
+                let order = Arc::clone(&order);
+                on_queue(&queue, move || order.lock().unwrap().push(i))
+            })
+            .collect();
+        for task in tasks {
+            task.await;
+        }
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn on_queue_sync_runs_inline_when_already_on_queue() {
+        let queue = SerialQueue::new("queue.reentrant");
+        let inner = queue.clone();
+        let result = on_queue_sync(&queue, move || on_queue_sync(&inner, || 1) + 1);
+        assert_eq!(result, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn on_queue_sync_resumes_panic_in_caller() {
+        let queue = SerialQueue::new("queue.panic");
+        on_queue_sync(&queue, || panic!("boom"));
+    }
+
+    #[test]
+    fn queue_work_is_reported_by_label() {
+        let queue = SerialQueue::new("queue.metrics");
+        on_queue_sync(&queue, || ());
+
+        let snapshot = crate::metrics();
+        let metrics = &snapshot.by_queue["queue.metrics"];
+        assert_eq!((metrics.dispatched, metrics.queued), (1, 0));
+        assert_eq!(metrics.run.count(), 1);
+        assert!(!snapshot.by_name.contains_key("queue.metrics"));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn queue_runs_on_thread_named_after_label() {
+        let queue = SerialQueue::new("queue.thread");
+        let name = on_queue_sync(&queue, || std::thread::current().name().map(str::to_owned));
+        assert_eq!(name.as_deref(), Some("queue.thread"));
+    }
+}
@@ -0,0 +1,153 @@
+use std::mem;
+use std::panic::Location;
+use std::sync::mpsc::{self, SyncSender};
+
+use crate::capacity::{Permit, MAIN_QUEUE};
+use crate::context::CallerContext;
+use crate::dispatch::{catch, submit, Job, Priority};
+use crate::error::DispatchError;
+use crate::main_loop;
+use crate::metrics::{Probe, Target};
+
+/// A scoped closure on its way to the main thread.
+///
+/// Fields drop in declaration order, so `run`, which may borrow from the
+/// caller's stack, is always gone before `done` disconnects and lets the
+/// caller return, even if the job is dropped without running.
+struct Scoped<'env, R> {
+    run: Option<Box<dyn FnOnce() -> Result<R, DispatchError> + Send + 'env>>,
+    _permit: Permit,
+    done: SyncSender<Result<R, DispatchError>>,
+}
+
+/// Runs `f` on the main thread and blocks until it returns, like
+/// [`on_main_sync`](crate::on_main_sync), but `f` may borrow from the caller.
+///
+/// Blocking until `f` has run, or has been dropped without running, is what
+/// makes the borrow sound, which is why there is no timeout variant. A panic
+/// in `f` is resumed in the caller. When called on the main thread, `f` runs
+/// inline.
+///
+/// ```ignore
+/// let config = load_config();
+/// let window = apple_main::on_main_scoped(|| Window::new(&config.window));
+/// ```
+#[track_caller]
+pub fn on_main_scoped<'env, F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'env,
+    R: Send + 'env,
+{
+    let location = Location::caller();
+    let result = if crate::is_main_thread() {
+        Probe::inline(Target::Main(None)).run(|| catch(f, location))
+    } else {
+        run_scoped(location, f)
+    };
+    result.unwrap_or_else(|e| e.raise())
+}
+
+fn run_scoped<'env, F, R>(location: &'static Location<'static>, f: F) -> Result<R, DispatchError>
+where
+    F: FnOnce() -> R + Send + 'env,
+    R: Send + 'env,
+{
+    if !main_loop::wait_until_running(None) {
+        return Err(DispatchError::NotRunning);
+    }
+
+    let probe = Probe::queued(Target::Main(None));
+    let context = CallerContext::capture();
+    let permit = MAIN_QUEUE
+        .acquire_blocking(None)
+        .expect("waiting without a deadline never times out");
+
+    let (tx, rx) = mpsc::sync_channel(1);
+    let scoped = Scoped {
+        run: Some(Box::new(move || {
+            probe.run(|| catch(|| context.scope(f), location))
+        })),
+        _permit: permit,
+        done: tx,
+    };
+    let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+        let mut scoped = scoped;
+        if let Some(run) = scoped.run.take() {
+            let _ = scoped.done.send(run());
+        }
+    });
+
+    // SAFETY: the job only borrows data that outlives `'env`, and this
+    // function does not return until `rx` yields. That happens once the job
+    // has sent its result after running `f`, or once the job was dropped,
+    // which drops `f` before the sender (see `Scoped`). Either way nothing
+    // borrowed is touched after we return. `recv` does not unwind.
+    let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'env>, Job>(job) };
+    submit(Priority::Normal, job);
+
+    rx.recv().unwrap_or(Err(DispatchError::ShuttingDown))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capacity::Capacity;
+
+    #[test]
+    fn dropped_job_releases_caller_after_closure() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = SetOnDrop(Arc::clone(&dropped));
+        let (tx, rx) = mpsc::sync_channel(1);
+        let scoped = Scoped {
+            run: Some(Box::new(move || {
+                let _guard = guard;
+                Ok(())
+            })),
+            _permit: Box::leak(Box::new(Capacity::new())).try_acquire().unwrap(),
+            done: tx,
+        };
+
+        let observer = std::thread::spawn(move || {
+            assert!(rx.recv().is_err());
+            dropped.load(Ordering::SeqCst)
+        });
+        drop(scoped);
+        assert!(observer.join().unwrap());
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use crate::on_main_scoped;
+
+        #[test]
+        fn on_main_scoped_borrows_from_caller() {
+            let config = String::from("vm.json");
+            let mut seen = Vec::new();
+
+            let len = on_main_scoped(|| {
+                seen.push(config.as_str());
+                config.len()
+            });
+            assert_eq!(len, 7);
+            assert_eq!(seen, ["vm.json"]);
+        }
+
+        #[test]
+        #[should_panic(expected = "boom")]
+        fn on_main_scoped_resumes_panic_in_caller() {
+            let config = String::from("boom");
+            on_main_scoped(|| panic!("{config}"));
+        }
+    }
+}
@@ -25,17 +25,25 @@ fn collect_tests() -> Vec<libtest_mimic::Trial> {
 /// Run all registered tests using libtest-mimic.
 ///
 /// On macOS, this starts CFRunLoop on the main thread so that `on_main()` and
-/// `on_main_sync()` work correctly. Tests run on the tokio runtime.
+/// `on_main_sync()` work correctly. Tests run on the tokio runtime. With the
+/// `emulated-main-thread` feature, other platforms park the main thread in the
+/// emulated main loop the same way.
 ///
 /// This function is called by the `test_main!()` macro.
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
 pub fn run_tests() -> ! {
+    #[cfg(not(target_os = "macos"))]
+    crate::__internal::record_main_thread();
+
     let args = libtest_mimic::Arguments::from_args();
     let tests = collect_tests();
 
-    let rt = crate::init_runtime();
+    crate::init_runtime();
 
-    rt.spawn(async move {
+    // Run the harness on its own thread rather than a runtime worker: each
+    // test calls `block_on`, which must not happen inside the runtime, and
+    // would tie up a worker that the tests' own tasks may need.
+    std::thread::spawn(move || {
         libtest_mimic::run(&args, tests).exit();
     });
 
@@ -47,7 +55,7 @@ pub fn run_tests() -> ! {
 /// On non-macOS platforms, this simply runs tests on the tokio runtime.
 ///
 /// This function is called by the `test_main!()` macro.
-#[cfg(not(target_os = "macos"))]
+#[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
 pub fn run_tests() -> ! {
     let args = libtest_mimic::Arguments::from_args();
     let tests = collect_tests();
@@ -0,0 +1,323 @@
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::dispatch::catch;
+use crate::executor::schedule_at;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+struct TimerState {
+    // Taken while the callback runs, and dropped once the timer is cancelled.
+    callback: Mutex<Option<Callback>>,
+    cancelled: AtomicBool,
+    location: &'static Location<'static>,
+}
+
+impl TimerState {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        self.callback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Runs the callback once. Returns `false` if the timer is done, because
+    /// it was cancelled or the callback panicked.
+    fn fire(&self) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+        let Some(mut callback) = self
+            .callback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        else {
+            return false;
+        };
+
+        if catch(&mut callback, self.location).is_err() {
+            self.cancelled.store(true, Ordering::Release);
+            return false;
+        }
+        if self.is_cancelled() {
+            return false;
+        }
+        *self.callback.lock().unwrap_or_else(|e| e.into_inner()) = Some(callback);
+        true
+    }
+}
+
+/// A handle to a timer scheduled by [`main_after`] or [`main_interval`].
+///
+/// Dropping the handle cancels the timer; call [`detach`](Self::detach) to
+/// keep it running without one.
+#[must_use = "dropping a MainTimer cancels it"]
+pub struct MainTimer {
+    state: Option<Arc<TimerState>>,
+}
+
+impl MainTimer {
+    /// Stops the timer. The callback does not run again, and is dropped
+    /// unless it is running right now, in which case it is dropped when it
+    /// returns.
+    pub fn cancel(&self) {
+        if let Some(state) = &self.state {
+            state.cancel();
+        }
+    }
+
+    /// Whether the timer was cancelled, or stopped because its callback
+    /// panicked. A [`main_after`] timer is not cancelled by firing.
+    pub fn is_cancelled(&self) -> bool {
+        self.state
+            .as_ref()
+            .is_some_and(|state| state.is_cancelled())
+    }
+
+    /// Lets the timer keep running after the handle is dropped.
+    pub fn detach(mut self) {
+        self.state = None;
+    }
+}
+
+impl Drop for MainTimer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[track_caller]
+fn start(callback: Callback) -> (MainTimer, Arc<TimerState>) {
+    let state = Arc::new(TimerState {
+        callback: Mutex::new(Some(callback)),
+        cancelled: AtomicBool::new(false),
+        location: Location::caller(),
+    });
+    let timer = MainTimer {
+        state: Some(Arc::clone(&state)),
+    };
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    {
+        let state = Arc::clone(&state);
+        crate::main_loop::on_not_running(move || state.cancel());
+    }
+    (timer, state)
+}
+
+/// Runs `f` on the main thread once `delay` has passed.
+///
+/// The timer is scheduled directly on the main run loop, with
+/// `dispatch_after` on macOS, so no tokio task is involved. Like
+/// [`spawn_main`](crate::spawn_main), non-macOS platforms run it on a
+/// dedicated background thread unless the main thread is emulated. If no main
+/// loop starts within the [grace period](crate::set_main_loop_grace_period),
+/// the timer is cancelled.
+///
+/// ```ignore
+/// let timeout = apple_main::main_after(Duration::from_secs(30), move || vm.stop());
+/// vm.start().await;
+/// timeout.cancel();
+/// ```
+#[track_caller]
+pub fn main_after<F>(delay: Duration, f: F) -> MainTimer
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut f = Some(f);
+    let (timer, state) = start(Box::new(move || {
+        if let Some(f) = f.take() {
+            f();
+        }
+    }));
+
+    schedule_at(
+        Instant::now() + delay,
+        Box::new(move || {
+            if state.fire() {
+                // Drop the spent callback now rather than with the handle.
+                state
+                    .callback
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .take();
+            }
+        }),
+    );
+    timer
+}
+
+/// Runs `f` on the main thread every `period`, starting one `period` from
+/// now, until the returned [`MainTimer`] is cancelled.
+///
+/// Ticks are scheduled against the start time, so they do not drift. If the
+/// main thread falls behind, missed ticks are skipped rather than run in a
+/// burst. A panic in `f` stops the timer.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+///
+/// ```ignore
+/// let poll = apple_main::main_interval(Duration::from_millis(500), move || {
+///     let _ = states.try_send(vm.state());
+/// });
+/// ```
+#[track_caller]
+pub fn main_interval<F>(period: Duration, f: F) -> MainTimer
+where
+    F: FnMut() + Send + 'static,
+{
+    assert!(!period.is_zero(), "main_interval period must be non-zero");
+
+    let (timer, state) = start(Box::new(f));
+    tick(state, period, Instant::now() + period);
+    timer
+}
+
+fn tick(state: Arc<TimerState>, period: Duration, deadline: Instant) {
+    schedule_at(
+        deadline,
+        Box::new(move || {
+            if state.fire() {
+                tick(
+                    state,
+                    period,
+                    next_deadline(deadline, period, Instant::now()),
+                );
+            }
+        }),
+    );
+}
+
+/// The first tick after `now` on the schedule of `deadline + n * period`.
+fn next_deadline(deadline: Instant, period: Duration, now: Instant) -> Instant {
+    let next = deadline + period;
+    if next > now {
+        return next;
+    }
+    let behind = (now - next).as_nanos() / period.as_nanos();
+    let skipped = u32::try_from(behind + 1).unwrap_or(u32::MAX);
+    next + period * skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_deadline_follows_schedule() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+
+        let next = next_deadline(start, period, start + Duration::from_millis(3));
+        assert_eq!(next, start + period);
+    }
+
+    #[test]
+    fn next_deadline_skips_missed_ticks() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+
+        let next = next_deadline(start, period, start + Duration::from_millis(35));
+        assert_eq!(next, start + Duration::from_millis(40));
+
+        let next = next_deadline(start, period, start + Duration::from_millis(20));
+        assert_eq!(next, start + Duration::from_millis(30));
+    }
+
+    #[test]
+    fn cancelled_timer_drops_callback_without_running_it() {
+        let dropped = Arc::new(());
+        let held = Arc::clone(&dropped);
+        let (timer, state) = start(Box::new(move || {
+            let _ = &held;
+            unreachable!("cancelled timer fired");
+        }));
+
+        timer.cancel();
+        assert!(timer.is_cancelled());
+        assert_eq!(Arc::strong_count(&dropped), 1);
+        assert!(!state.fire());
+    }
+
+    #[test]
+    fn panicking_callback_stops_timer() {
+        let (timer, state) = start(Box::new(|| panic!("tick failed")));
+
+        assert!(!state.fire());
+        assert!(timer.is_cancelled());
+    }
+
+    #[test]
+    fn dropping_handle_cancels_but_detach_does_not() {
+        let (timer, state) = start(Box::new(|| {}));
+        drop(timer);
+        assert!(state.is_cancelled());
+
+        let (timer, state) = start(Box::new(|| {}));
+        timer.detach();
+        assert!(!state.is_cancelled());
+        assert!(state.fire());
+    }
+
+    // Never registers a main loop: other tests rely on none running.
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+    mod without_main_loop {
+        use crate::main_after;
+        use std::time::{Duration, Instant};
+
+        #[test]
+        fn timer_is_cancelled_without_main_loop() {
+            crate::set_main_loop_grace_period(Duration::from_millis(10));
+            let timer = main_after(Duration::from_secs(60), || {});
+
+            let give_up = Instant::now() + Duration::from_secs(10);
+            while !timer.is_cancelled() {
+                assert!(Instant::now() < give_up, "timer was not cancelled");
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
+    mod non_macos {
+        use crate::{main_after, main_interval};
+        use std::sync::mpsc;
+        use std::time::{Duration, Instant};
+
+        #[test]
+        fn main_after_runs_once_after_delay() {
+            let (tx, rx) = mpsc::channel();
+            let start = Instant::now();
+            main_after(Duration::from_millis(20), move || tx.send(()).unwrap()).detach();
+
+            rx.recv().unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(20));
+            assert!(rx.recv().is_err());
+        }
+
+        #[test]
+        fn main_interval_ticks_until_cancelled() {
+            let (tx, rx) = mpsc::channel();
+            let mut ticks = 0;
+            let timer = main_interval(Duration::from_millis(5), move || {
+                ticks += 1;
+                let _ = tx.send(ticks);
+            });
+
+            let received: Vec<_> = rx.iter().take(3).collect();
+            assert_eq!(received, vec![1, 2, 3]);
+            drop(timer);
+            // The callback, and with it the sender, is dropped on cancel.
+            while rx.recv().is_ok() {}
+        }
+    }
+}
@@ -24,8 +24,12 @@ async fn test_async_operation() {
 #[apple_main::harness_test]
 async fn test_is_main_thread_available() {
     let is_main = apple_main::is_main_thread();
-    // Tests run on tokio threads, not main (CFRunLoop runs on main for dispatch)
-    assert!(!is_main);
+    // Tests run on tokio threads, not main (CFRunLoop runs on main for dispatch).
+    // Elsewhere every thread counts as the main thread.
+    assert_eq!(
+        is_main,
+        !cfg!(any(target_os = "macos", feature = "emulated-main-thread"))
+    );
 }
 
 #[apple_main::harness_test]
@@ -40,4 +44,520 @@ async fn test_on_main_sync_dispatch() {
     assert_eq!(result, 123);
 }
 
+#[apple_main::harness_test]
+async fn test_on_main_dropped_task_is_skipped() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    // Keep the main thread busy so the second task is still queued when dropped.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&ran);
+    drop(apple_main::on_main(move || {
+        flag.store(true, Ordering::SeqCst)
+    }));
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    apple_main::on_main(|| ()).await;
+
+    assert!(!ran.load(Ordering::SeqCst));
+}
+
+#[apple_main::harness_test]
+async fn test_on_main_panic_propagates_and_main_thread_survives() {
+    let result = tokio::spawn(async { apple_main::on_main(|| panic!("boom")).await }).await;
+    let payload = result.unwrap_err().into_panic();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+
+    let result = apple_main::on_main(|| 42).await;
+    assert_eq!(result, 42);
+}
+
+#[apple_main::harness_test]
+async fn test_try_on_main_reports_panic() {
+    let err = apple_main::try_on_main(|| panic!("boom"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, apple_main::DispatchError::Panicked(_)));
+
+    let result = apple_main::try_on_main_sync(|| 123);
+    assert_eq!(result.unwrap(), 123);
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_on_main_timeouts_report_whether_closure_started() {
+    use apple_main::DispatchError;
+    use std::time::Duration;
+
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let result = tokio::task::spawn_blocking(|| {
+        apple_main::on_main_sync_timeout(Duration::from_millis(50), || 42)
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        result,
+        Err(DispatchError::TimedOut { started: false })
+    ));
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+
+    // Hold the main thread past the deadline once the closure has started.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let result = apple_main::on_main_timeout(Duration::from_millis(500), move || {
+        let _ = release_rx.recv();
+    })
+    .await;
+    release_tx.send(()).unwrap();
+    assert!(matches!(
+        result,
+        Err(DispatchError::TimedOut { started: true })
+    ));
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_on_main_runs_queued_work_by_priority() {
+    use apple_main::Priority;
+    use std::sync::{Arc, Mutex};
+
+    // Keep the main thread busy so all three tasks are queued together.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let tasks: Vec<_> = [Priority::Low, Priority::Normal, Priority::High]
+        .into_iter()
+        .map(|priority| {
+            let order = Arc::clone(&order);
+            apple_main::on_main_with_priority(priority, move || {
+                order.lock().unwrap().push(priority)
+            })
+        })
+        .collect();
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    for task in tasks {
+        task.await;
+    }
+
+    assert_eq!(
+        *order.lock().unwrap(),
+        [Priority::High, Priority::Normal, Priority::Low]
+    );
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_on_main_coalesced_runs_latest_queued_closure_once() {
+    use std::sync::Arc;
+
+    // Keep the main thread busy so the coalesced calls are all still queued.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let calls: Vec<_> = (1..=3)
+        .map(|value| {
+            let runs = Arc::clone(&runs);
+            apple_main::on_main_coalesced("refresh", move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                value
+            })
+        })
+        .collect();
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    for call in calls {
+        assert_eq!(call.await, 3);
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_on_main_named_reports_queued_work_in_metrics() {
+    use std::time::Duration;
+
+    // Keep the main thread busy so the named task waits in the queue.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+    let task = apple_main::on_main_named("harness.metrics", || {
+        std::thread::sleep(Duration::from_millis(5))
+    });
+
+    let named = &apple_main::metrics().by_name["harness.metrics"];
+    assert_eq!((named.dispatched, named.queued), (1, 1));
+
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    release_tx.send(()).unwrap();
+    blocker.await;
+    task.await;
+
+    let named = &apple_main::metrics().by_name["harness.metrics"];
+    assert_eq!((named.dispatched, named.queued), (1, 0));
+    assert!(named.wait.sum() >= Duration::from_millis(5));
+    assert!(named.run.sum() >= Duration::from_millis(5));
+    assert!(apple_main::metrics()
+        .to_prometheus()
+        .contains("apple_main_named_run_seconds_count{name=\"harness.metrics\"} 1\n"));
+}
+
+#[apple_main::harness_test]
+async fn test_on_main_sync_runs_inline_when_already_on_main_thread() {
+    // Would deadlock on the main queue without the inline fast path.
+    let result = apple_main::on_main(|| apple_main::on_main_sync(|| 1) + 1).await;
+    assert_eq!(result, 2);
+
+    let result = apple_main::spawn_main(|| async { apple_main::on_main(|| 3).await })
+        .await
+        .unwrap();
+    assert_eq!(result, 3);
+}
+
+#[derive(Clone)]
+struct Window {
+    title: String,
+}
+
+impl Window {
+    #[apple_main::main_thread(dispatch)]
+    fn title_on_main(&self) -> (String, bool) {
+        (self.title.clone(), apple_main::is_main_thread())
+    }
+
+    #[apple_main::main_thread(dispatch)]
+    fn into_title(self, suffix: String) -> String {
+        self.title + &suffix
+    }
+
+    #[apple_main::main_thread(dispatch)]
+    fn into_shouted_title(mut self) -> String {
+        self.title.make_ascii_uppercase();
+        self.title
+    }
+
+    #[apple_main::main_thread(dispatch)]
+    fn labelled_title(&self) -> String {
+        struct Label(&'static str);
+
+        impl Label {
+            fn get(&self) -> &'static str {
+                self.0
+            }
+        }
+
+        format!("{}: {}", Label("window").get(), self.title)
+    }
+
+    #[apple_main::main_thread]
+    fn assert_on_main(&self) -> usize {
+        self.title.len()
+    }
+}
+
+#[apple_main::main_thread(dispatch)]
+fn add_on_main(a: u32, b: u32) -> Result<u32, String> {
+    let sum = a.checked_add(b).ok_or("overflow")?;
+    Ok(sum)
+}
+
+#[apple_main::harness_test]
+async fn test_main_thread_attribute_dispatches_body_to_main_thread() {
+    let window = Window {
+        title: "main".into(),
+    };
+    assert_eq!(window.title_on_main().await, ("main".to_string(), true));
+    assert_eq!(window.labelled_title().await, "window: main");
+    assert_eq!(window.clone().into_shouted_title().await, "MAIN");
+    assert_eq!(window.into_title("!".into()).await, "main!");
+    assert_eq!(add_on_main(1, 2).await, Ok(3));
+    assert!(add_on_main(u32::MAX, 1).await.is_err());
+}
+
+#[apple_main::harness_test]
+async fn test_main_thread_attribute_asserts_main_thread() {
+    let window = Window {
+        title: "main".into(),
+    };
+    let on_main = window.clone();
+    assert_eq!(
+        apple_main::on_main(move || on_main.assert_on_main()).await,
+        4
+    );
+
+    #[cfg(all(
+        debug_assertions,
+        any(target_os = "macos", feature = "emulated-main-thread")
+    ))]
+    {
+        let panic = std::panic::catch_unwind(|| window.assert_on_main()).unwrap_err();
+        let message = panic.downcast_ref::<&str>().copied().unwrap_or_default();
+        assert!(message.contains("`assert_on_main` must be called on the main thread"));
+    }
+}
+
+#[apple_main::harness_test]
+async fn test_main_channels_cross_between_main_thread_and_tokio() {
+    let (tx, mut rx) = apple_main::main_channel(4);
+    let consumer = apple_main::main_consumer(2, move |value: u32| {
+        // A plain main-thread callback, with no async context.
+        tx.try_send((value, apple_main::is_main_thread())).unwrap();
+    });
+
+    for value in 0..3 {
+        consumer.send(value).await.unwrap();
+    }
+    drop(consumer);
+
+    let mut received = Vec::new();
+    while let Some(item) = rx.recv().await {
+        received.push(item);
+    }
+    assert_eq!(received, vec![(0, true), (1, true), (2, true)]);
+}
+
+#[apple_main::harness_test]
+async fn test_call_on_main_with_completion_bridges_callback() {
+    let result = apple_main::call_on_main_with_completion(|done| {
+        let on_main = apple_main::is_main_thread();
+        // Completion handlers typically fire on another queue.
+        std::thread::spawn(move || done.complete(on_main));
+    })
+    .await;
+    assert!(result.unwrap());
+
+    let result = apple_main::call_on_main_with_completion::<(), _>(drop).await;
+    assert!(matches!(
+        result,
+        Err(apple_main::DispatchError::NeverCompleted)
+    ));
+}
+
+#[apple_main::harness_test]
+async fn test_main_timers_fire_on_main_thread() {
+    use std::time::Duration;
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    apple_main::main_after(Duration::from_millis(10), move || {
+        let _ = tx.send(apple_main::is_main_thread());
+    })
+    .detach();
+    assert!(rx.await.unwrap());
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let timer = apple_main::main_interval(Duration::from_millis(5), move || {
+        let _ = tx.send(apple_main::is_main_thread());
+    });
+    for _ in 0..3 {
+        assert!(rx.recv().await.unwrap());
+    }
+    timer.cancel();
+    // Cancelling drops the callback, closing the channel.
+    while rx.recv().await.is_some() {}
+}
+
+tokio::task_local! {
+    static REQUEST_ID: u64;
+}
+
+#[apple_main::harness_test]
+async fn test_task_locals_propagate_into_main_thread_closures() {
+    apple_main::context::propagate_task_local(&REQUEST_ID);
+
+    let seen = REQUEST_ID
+        .scope(7, async {
+            let on_main = apple_main::on_main(|| REQUEST_ID.get()).await;
+            let on_main_sync = apple_main::on_main_sync(|| REQUEST_ID.get());
+            (on_main, on_main_sync)
+        })
+        .await;
+    assert_eq!(seen, (7, 7));
+}
+
+#[apple_main::harness_test]
+async fn test_on_main_scoped_borrows_from_caller_stack() {
+    let config = String::from("vm.json");
+    let mut on_main = Vec::new();
+
+    let len = apple_main::on_main_scoped(|| {
+        on_main.push(apple_main::is_main_thread());
+        config.len()
+    });
+    assert_eq!(len, 7);
+    assert_eq!(on_main, [true]);
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_main_batch_runs_without_interleaving() {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    let (started_tx, started_rx) = mpsc::channel();
+    let (queued_tx, queued_rx) = mpsc::channel::<()>();
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    let first = Arc::clone(&order);
+    let mut batch = apple_main::MainBatch::new().then(move || {
+        first.lock().unwrap().push(0);
+        // Hold the main thread until the interloper is queued behind the batch.
+        started_tx.send(()).unwrap();
+        queued_rx.recv().unwrap();
+    });
+    for i in 1..3 {
+        let order = Arc::clone(&order);
+        batch = batch.then(move || order.lock().unwrap().push(i));
+    }
+    let batch = batch.run();
+
+    tokio::task::spawn_blocking(move || started_rx.recv().unwrap())
+        .await
+        .unwrap();
+    let other = Arc::clone(&order);
+    let interloper = apple_main::on_main_with_priority(apple_main::Priority::High, move || {
+        other.lock().unwrap().push(99);
+    });
+    queued_tx.send(()).unwrap();
+
+    batch.await;
+    interloper.await;
+    assert_eq!(*order.lock().unwrap(), [0, 1, 2, 99]);
+}
+
+#[apple_main::harness_test]
+async fn test_main_loop_observers_fire_on_main_thread() {
+    use apple_main::MainLoopEvent;
+    use std::time::Duration;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let observer = apple_main::observe_main_loop(move |event| {
+        let _ = tx.send((event, apple_main::is_main_thread()));
+    });
+
+    // Let the loop go to sleep, then wake it.
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    apple_main::on_main(|| ()).await;
+
+    let (mut before, mut after) = (false, false);
+    while !(before && after) {
+        let (event, on_main) = rx.recv().await.unwrap();
+        assert!(on_main);
+        before |= event == MainLoopEvent::BeforeWaiting;
+        after |= event == MainLoopEvent::AfterWaiting;
+    }
+
+    drop(observer);
+    // Removing the observer drops the callback, closing the channel.
+    while rx.recv().await.is_some() {}
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_on_main_idle_runs_after_queued_work() {
+    use std::sync::{Arc, Mutex};
+
+    // Keep the main thread busy so both closures are queued before it idles.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let idle_order = Arc::clone(&order);
+    let idle = apple_main::on_main_idle(move || {
+        idle_order.lock().unwrap().push("idle");
+        apple_main::is_main_thread()
+    });
+    let queued_order = Arc::clone(&order);
+    let queued = apple_main::on_main(move || queued_order.lock().unwrap().push("queued"));
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    queued.await;
+    assert!(idle.await);
+    assert_eq!(*order.lock().unwrap(), ["queued", "idle"]);
+}
+
+#[apple_main::harness_test]
+async fn test_task_created_on_main_thread_runs_there_when_polled_elsewhere() {
+    // Created on the main thread, but awaited on a tokio worker.
+    let task = apple_main::on_main(|| apple_main::on_main(apple_main::is_main_thread)).await;
+    assert!(tokio::spawn(task).await.unwrap());
+
+    let task =
+        apple_main::on_main(|| apple_main::on_main_with(|_| apple_main::is_main_thread())).await;
+    assert!(task.await);
+}
+
+#[apple_main::harness_test]
+async fn test_coalesced_call_made_on_main_thread_runs_there_when_polled_elsewhere() {
+    let call = apple_main::on_main(|| {
+        apple_main::on_main_coalesced("polled-elsewhere", apple_main::is_main_thread)
+    })
+    .await;
+    assert!(tokio::spawn(call).await.unwrap());
+}
+
+#[apple_main::harness_test]
+async fn test_spawn_main_runs_non_send_future_on_main_thread() {
+    let handle = apple_main::spawn_main(|| async {
+        let value = std::rc::Rc::new(apple_main::is_main_thread());
+        tokio::task::yield_now().await;
+        *value
+    });
+    assert!(handle.await.unwrap());
+}
+
+#[apple_main::harness_test]
+async fn test_main_bound_keeps_value_on_main_thread() {
+    let bound = apple_main::on_main_bound(|| std::rc::Rc::new(41)).await;
+    let result = bound.with(|value| **value + 1).await;
+    assert_eq!(result, 42);
+
+    let bound_clone = bound.clone();
+    let result = tokio::task::spawn_blocking(move || bound_clone.with_sync(|value| **value))
+        .await
+        .unwrap();
+    assert_eq!(result, 41);
+}
+
+#[apple_main::harness_test]
+async fn test_actor_handles_messages_on_main_thread() {
+    struct OnMain;
+
+    impl apple_main::MainActor for OnMain {
+        type Message = ();
+        type Reply = bool;
+
+        fn handle(&mut self, _: ()) -> bool {
+            apple_main::is_main_thread()
+        }
+    }
+
+    let actor = apple_main::spawn_actor(4, || OnMain);
+    assert!(actor.ask(()).await.unwrap());
+    actor.stop().await;
+    actor.join().await.unwrap();
+}
+
 apple_main::test_main!();
@@ -26,9 +26,8 @@ async fn test_macro_with_spawn() {
     assert_eq!(result, 100);
 }
 
-#[cfg(not(target_os = "macos"))]
+#[cfg(not(any(target_os = "macos", feature = "emulated-main-thread")))]
 mod non_macos {
-    use super::*;
     use apple_main::{on_main, on_main_sync};
 
     #[test]
@@ -1,5 +1,12 @@
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
 #[apple_main::main]
 async fn main() {
+    if std::env::var_os("APPLE_MAIN_PANIC").is_some() {
+        panic!("main panicked");
+    }
+
     let result = async { 42 }.await;
     assert_eq!(result, 42);
 
@@ -7,11 +14,31 @@ async fn main() {
     let spawned_result = handle.await.unwrap();
     assert_eq!(spawned_result, 100);
 
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
     {
         apple_main::on_main(|| {
             assert!(apple_main::is_main_thread());
         })
         .await;
     }
+
+    // A panicking body exits like a panicking `main`, rather than leaving the
+    // main loop running.
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .env("APPLE_MAIN_PANIC", "1")
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let give_up = Instant::now() + Duration::from_secs(30);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > give_up {
+            child.kill().unwrap();
+            panic!("panicking main did not exit");
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(status.code(), Some(101));
 }
@@ -0,0 +1,111 @@
+//! Runs on its own so the global main-queue limit cannot slow down or time
+//! out tests in other binaries.
+
+/// Held by each test while it changes the global limit, since the harness
+/// runs tests in parallel.
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+static LIMIT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_main_queue_limit_applies_backpressure() {
+    use apple_main::DispatchError;
+    use std::time::Duration;
+
+    let _limit = LIMIT.lock().await;
+    apple_main::set_main_queue_limit(Some(2));
+    assert_eq!(apple_main::main_queue_limit(), Some(2));
+
+    // Keep the main thread busy so the queue fills up.
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+    let queued = apple_main::on_main(|| 1);
+
+    assert!(matches!(
+        apple_main::try_on_main_now(|| 2),
+        Err(DispatchError::QueueFull)
+    ));
+    let mut waiting = apple_main::on_main(|| 3);
+    let blocked_sync = tokio::task::spawn_blocking(|| apple_main::on_main_sync(|| 4));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(20), &mut waiting)
+            .await
+            .is_err()
+    );
+    assert!(!blocked_sync.is_finished());
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    assert_eq!(queued.await, 1);
+    assert_eq!(waiting.await, 3);
+    assert_eq!(blocked_sync.await.unwrap(), 4);
+    assert_eq!(apple_main::try_on_main_now(|| 5).unwrap().await.unwrap(), 5);
+
+    apple_main::set_main_queue_limit(None);
+    assert_eq!(apple_main::main_queue_limit(), None);
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_aborted_waiting_task_never_runs() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let _limit = LIMIT.lock().await;
+    apple_main::set_main_queue_limit(Some(1));
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&ran);
+    let waiting = apple_main::try_on_main(move || flag.store(true, Ordering::SeqCst));
+    waiting.abort();
+    assert!(matches!(
+        waiting.await,
+        Err(apple_main::DispatchError::Cancelled)
+    ));
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    apple_main::set_main_queue_limit(None);
+    apple_main::on_main(|| ()).await;
+    assert!(!ran.load(Ordering::SeqCst));
+}
+
+#[cfg(any(target_os = "macos", feature = "emulated-main-thread"))]
+#[apple_main::harness_test]
+async fn test_coalesced_calls_count_toward_limit() {
+    use apple_main::DispatchError;
+    use std::time::Duration;
+
+    let _limit = LIMIT.lock().await;
+    apple_main::set_main_queue_limit(Some(2));
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let blocker = apple_main::on_main(move || {
+        let _ = release_rx.recv();
+    });
+
+    let queued = apple_main::on_main_coalesced("limit.queued", || 1);
+    assert!(matches!(
+        apple_main::try_on_main_now(|| 2),
+        Err(DispatchError::QueueFull)
+    ));
+    let mut waiting = Box::pin(apple_main::on_main_coalesced("limit.waiting", || 3));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(20), &mut waiting)
+            .await
+            .is_err()
+    );
+
+    release_tx.send(()).unwrap();
+    blocker.await;
+    assert_eq!(queued.await, 1);
+    assert_eq!(waiting.await, 3);
+    apple_main::set_main_queue_limit(None);
+}
+
+apple_main::test_main!();